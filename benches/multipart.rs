@@ -0,0 +1,94 @@
+// Throughput of multipart uploads through a running server.
+//
+//     cargo bench --bench multipart
+//
+// Each payload is sent as one file part of a 256MB upload over localhost, the
+// handler copies the part to a sink.
+use rustweb::context::{Context, ContextFn};
+use std::io::{self, prelude::*};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PORT: u32 = 7899;
+const SIZE: usize = 256 << 20;
+const BOUNDARY: &str = "------------------------974767299852498929531610575";
+
+fn upload_handler(mut c: Context) {
+    let mut total = 0;
+    for part in c.request.multipart() {
+        match part {
+            Ok(mut part) => total += io::copy(&mut part, &mut io::sink()).unwrap_or(0),
+            Err(_) => break,
+        }
+    }
+    c.json(&total);
+}
+
+// Random bytes, like a compressed file
+fn random(size: usize) -> Vec<u8> {
+    let mut state = 0x9e3779b97f4a7c15u64;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+// Near-misses of the delimiter all over, the worst case for the search
+fn near_misses(size: usize) -> Vec<u8> {
+    let near = format!("\r\n{}X", &BOUNDARY[..BOUNDARY.len() - 1]);
+    near.bytes().cycle().take(size).collect()
+}
+
+fn upload(name: &str, content: &[u8]) {
+    let head = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n",
+        BOUNDARY, name
+    );
+    let tail = format!("\r\n--{}--\r\n", BOUNDARY);
+    let length = head.len() + content.len() + tail.len();
+
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", PORT)).unwrap();
+    let start = Instant::now();
+    write!(
+        stream,
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary={}\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY, length
+    )
+    .unwrap();
+    stream.write_all(head.as_bytes()).unwrap();
+    for chunk in content.chunks(1 << 20) {
+        stream.write_all(chunk).unwrap();
+    }
+    stream.write_all(tail.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let elapsed = start.elapsed();
+    let status = response.lines().next().unwrap_or("");
+
+    let mb = content.len() as f64 / (1 << 20) as f64;
+    eprintln!(
+        "{:<12} {:>6.0} MB in {:>6.2}s  {:>8.1} MB/s  {}",
+        name,
+        mb,
+        elapsed.as_secs_f64(),
+        mb / elapsed.as_secs_f64(),
+        status
+    );
+}
+
+fn main() {
+    let web = rustweb::build_server("127.0.0.1", PORT);
+    web.body_limit(1 << 30);
+    web.post("/upload", upload_handler);
+    thread::spawn(move || web.run());
+    thread::sleep(Duration::from_millis(200));
+
+    upload("random", &random(SIZE));
+    upload("near-misses", &near_misses(SIZE));
+}
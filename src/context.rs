@@ -1,16 +1,33 @@
+use crate::cookie::Cookie;
+use crate::header::HeaderMap;
 use crate::request::Request;
 use crate::response::status::Status;
-use crate::response::{FileBody, JsonBody, NoneContent, Response};
+use crate::response::{FileBody, IntoResponse, JsonBody, NoneContent, Response};
+use crate::session::{self, Session};
+use crate::stream::Stream;
 use crate::Connection;
-use std::collections::HashMap;
+use serde::Serialize;
 use std::fs::File;
-use std::io::{BufReader, Error};
+use std::io::{self, BufReader, Error, ErrorKind};
 use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 pub struct Context {
     pub request: Request,
     pub stream: TcpStream,
+    // Shared with the runtime, which takes it back when an async handler is done
+    pub(crate) extra: Arc<Mutex<Extra>>,
+    #[cfg(feature = "event-loop")]
+    pub(crate) keep_alive: Option<crate::event_loop::KeepAlive>,
+}
+
+// What goes out with the response besides the response itself
+#[derive(Default)]
+pub(crate) struct Extra {
+    // Extra header fields, e.g. cookies
+    pub(crate) header: HeaderMap,
+    pub(crate) session: Option<Session>,
 }
 
 impl Context {
@@ -19,48 +36,103 @@ impl Context {
             .set_read_timeout(Some(Duration::from_secs(1)))
             .unwrap();
         let stream_clone = conn.stream.try_clone().unwrap();
-        let reader: BufReader<TcpStream> = BufReader::new(stream_clone);
+        let reader: BufReader<Stream> = BufReader::new(Stream::new(conn.buffered, stream_clone));
         let request = Request::new(reader, conn.address);
 
         let mut context = Context {
             request,
             stream: conn.stream,
+            extra: Arc::default(),
+            #[cfg(feature = "event-loop")]
+            keep_alive: conn.keep_alive,
         };
 
         match context.request.init() {
             Ok(()) => Ok(context),
             Err(err) => {
-                // context.error();
+                // Malformed requests still get an answer
+                if err.kind() == ErrorKind::InvalidData {
+                    context.error_with_status(Status::BadRequest);
+                }
                 return Result::Err(err);
             }
         }
     }
 }
 
+impl Context {
+    // The session of this request, loaded on first use and saved with the response
+    pub fn session(&mut self) -> Session {
+        let mut extra = self.extra.lock().unwrap();
+        let session = extra
+            .session
+            .get_or_insert_with(|| session::load(&self.request));
+        session.clone()
+    }
+
+    fn send_json<T: Serialize + ?Sized>(&mut self, status: Status, value: &T, pretty: bool) {
+        match JsonBody::from_value(value, pretty) {
+            Ok(body) => {
+                let mut response = Response::new(status, HeaderMap::new(), Box::new(body));
+                response.response(self).unwrap();
+            }
+            Err(err) => {
+                eprintln!("Error occurred when serialize json: {}", err);
+                self.error_with_status(Status::InternalServerError);
+            }
+        }
+    }
+}
+
 pub trait ContextFn {
     fn ok(&mut self);
-    fn json(&mut self, content: &[u8]);
+    fn json<T: Serialize + ?Sized>(&mut self, value: &T);
+    fn json_pretty<T: Serialize + ?Sized>(&mut self, value: &T);
+    fn json_with_status<T: Serialize + ?Sized>(&mut self, status: Status, value: &T);
+    fn json_raw(&mut self, content: &[u8]);
     fn file(&mut self, file: File, filename: String);
     fn error(&mut self);
     fn error_with_status(&mut self, status: Status);
+    fn respond(&mut self, response: impl IntoResponse);
+    fn add_header(&mut self, key: &str, value: &str) -> io::Result<()>;
+    fn add_cookie(&mut self, cookie: &Cookie) -> io::Result<()>;
+    fn add_signed_cookie(&mut self, cookie: &Cookie) -> io::Result<()>;
+    fn add_private_cookie(&mut self, cookie: &Cookie) -> io::Result<()>;
 }
 
 impl ContextFn for Context {
     fn ok(&mut self) {
         let content = b"{\"code\":200,\"message\":\"Upload finish!\"}";
-        self.json(content);
+        self.json_raw(content);
+    }
+
+    // Serialize `value` as the JSON body of a 200 response
+    fn json<T: Serialize + ?Sized>(&mut self, value: &T) {
+        self.send_json(Status::OK, value, false);
+    }
+
+    fn json_pretty<T: Serialize + ?Sized>(&mut self, value: &T) {
+        self.send_json(Status::OK, value, true);
     }
 
-    fn json(&mut self, content: &[u8]) {
-        let mut response =
-            Response::new(Status::OK, HashMap::new(), Box::new(JsonBody::new(content)));
+    fn json_with_status<T: Serialize + ?Sized>(&mut self, status: Status, value: &T) {
+        self.send_json(status, value, false);
+    }
+
+    // Send already encoded JSON as is
+    fn json_raw(&mut self, content: &[u8]) {
+        let mut response = Response::new(
+            Status::OK,
+            HeaderMap::new(),
+            Box::new(JsonBody::new(content)),
+        );
         response.response(self).unwrap();
     }
 
     fn file(&mut self, file: File, filename: String) {
         let disposition = format!("attachment; filename={}", filename);
         let body = FileBody::new(file, "application/octet-stream", disposition);
-        let mut response = Response::new(Status::OK, HashMap::new(), Box::new(body));
+        let mut response = Response::new(Status::OK, HeaderMap::new(), Box::new(body));
         response.response(self).unwrap()
     }
 
@@ -70,13 +142,58 @@ impl ContextFn for Context {
             mime_type: "text/html",
             disposition: "".to_string(),
         };
-        let mut response = Response::new(Status::NotFound, HashMap::new(), Box::new(body));
+        let mut response = Response::new(Status::NotFound, HeaderMap::new(), Box::new(body));
 
         response.response(self).unwrap();
     }
 
     fn error_with_status(&mut self, status: Status) {
-        let mut response = Response::new(status, HashMap::new(), Box::new(NoneContent));
+        let mut response = Response::new(status, HeaderMap::new(), Box::new(NoneContent));
         response.response(self).unwrap();
     }
+
+    // Send anything that converts into a response, e.g. an `ExtractError` as 400
+    fn respond(&mut self, response: impl IntoResponse) {
+        response.into_response().response(self).unwrap();
+    }
+
+    // Header fields added here are sent with whatever response follows
+    fn add_header(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.extra.lock().unwrap().header.append(key, value)
+    }
+
+    fn add_cookie(&mut self, cookie: &Cookie) -> io::Result<()> {
+        cookie.validate()?;
+        let mut extra = self.extra.lock().unwrap();
+        extra.header.append("Set-Cookie", &cookie.to_string())
+    }
+
+    // Readable by the client but tamper-proof, see `Request::signed_cookie`
+    fn add_signed_cookie(&mut self, cookie: &Cookie) -> io::Result<()> {
+        self.add_cookie(&cookie.signed()?)
+    }
+
+    // Encrypted, see `Request::private_cookie`
+    fn add_private_cookie(&mut self, cookie: &Cookie) -> io::Result<()> {
+        self.add_cookie(&cookie.private()?)
+    }
+}
+
+// A context for a request read from `raw` in tests, the client is gone already
+#[cfg(test)]
+pub(crate) fn from_raw(raw: &[u8]) -> Context {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, address) = listener.accept().unwrap();
+    drop(client);
+    Context::new(Connection {
+        address,
+        stream,
+        buffered: raw.to_vec(),
+        #[cfg(feature = "event-loop")]
+        keep_alive: None,
+    })
+    .unwrap()
 }
@@ -0,0 +1,260 @@
+use crate::header::is_token;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub mod secure;
+
+/*
+A cookie to send with `Set-Cookie`, one header per cookie.
+
+    let cookie = Cookie::new("session", "38afes7a8")
+        .path("/")
+        .max_age(Duration::from_secs(3600))
+        .http_only(true)
+        .same_site(SameSite::Lax);
+
+    Set-Cookie: session=38afes7a8; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax
+*/
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<Duration>,
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    // A cookie that makes the client delete `name`
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "")
+            .max_age(Duration::ZERO)
+            .expires(UNIX_EPOCH)
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    // `SameSite=None` is only accepted by browsers together with `Secure`
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    // The same cookie with the value signed, see `secure`
+    pub fn signed(&self) -> Result<Self> {
+        let mut cookie = self.clone();
+        cookie.value = secure::sign(&self.name, &self.value)?;
+        Ok(cookie)
+    }
+
+    // The same cookie with the value encrypted, see `secure`
+    pub fn private(&self) -> Result<Self> {
+        let mut cookie = self.clone();
+        cookie.value = secure::encrypt(&self.name, &self.value)?;
+        Ok(cookie)
+    }
+
+    // Check the name and value against RFC 6265 so the cookie can't break the header
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || !self.name.bytes().all(is_token) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid cookie name: {:?}", self.name),
+            ));
+        }
+        if !self.value.bytes().all(is_cookie_octet) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value for cookie {}", self.name),
+            ));
+        }
+        for attribute in [&self.path, &self.domain].into_iter().flatten() {
+            if attribute.bytes().any(|c| c == b';' || c.is_ascii_control()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid attribute for cookie {}", self.name),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The `Set-Cookie` header value
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict")?,
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax")?,
+            Some(SameSite::None) => write!(f, "; SameSite=None")?,
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+// Cookie: name=value; name2=value2
+pub fn parse_cookie_header(header: &str) -> Vec<(String, String)> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (name.trim().to_string(), value.to_string())
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+// IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let days = secs / 86400;
+    let (hour, min, sec) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        min,
+        sec
+    )
+}
+
+// cookie-octet: printable ASCII except space, '"', ',', ';' and '\'
+fn is_cookie_octet(c: u8) -> bool {
+    (0x21..=0x7e).contains(&c) && !b"\",;\\".contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(secs: u64) -> String {
+        http_date(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(date(1709251199), "Thu, 29 Feb 2024 23:59:59 GMT");
+        assert_eq!(date(4102444800), "Fri, 01 Jan 2100 00:00:00 GMT");
+        // Before the epoch is clamped to it
+        assert_eq!(
+            http_date(UNIX_EPOCH - Duration::from_secs(1)),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn cookie_header() {
+        let pairs = parse_cookie_header(r#"a=1; b = "two" ;c=x=y; novalue; =empty; d="#);
+        let pairs: Vec<(&str, &str)> = pairs
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(pairs, [("a", "1"), ("b", "two"), ("c", "x=y"), ("d", "")]);
+        assert!(parse_cookie_header("").is_empty());
+    }
+}
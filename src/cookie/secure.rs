@@ -0,0 +1,229 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::OnceLock;
+
+/*
+Signed and private (encrypted) cookie values.
+
+Signed:  base64url(HMAC-SHA256(name "=" value)) "." value
+         readable by the client, but any change is detected
+Private: base64url(nonce || AES-256-GCM(value, aad = name))
+         neither readable nor changeable by the client
+
+Both bind the value to the cookie name, so a value can't be moved to another cookie.
+The keys are derived from the server keys set with `RustWeb::cookie_keys`. New
+values use the first key, values made with the previous keys are still accepted so
+keys can be rotated without logging everyone out.
+*/
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+
+// Shortest server key accepted
+pub const MIN_KEY_LEN: usize = 32;
+
+struct Key {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl Key {
+    fn derive(master: &[u8]) -> Self {
+        Self {
+            signing: hmac(master, b"rustweb cookie signing"),
+            encryption: hmac(master, b"rustweb cookie encryption"),
+        }
+    }
+}
+
+static KEYS: OnceLock<Vec<Key>> = OnceLock::new();
+
+// Keys can't change once cookies were made with them, a second call panics
+pub fn set_keys(current: &[u8], previous: &[&[u8]]) {
+    assert!(
+        current.len() >= MIN_KEY_LEN && previous.iter().all(|key| key.len() >= MIN_KEY_LEN),
+        "cookie keys must be at least {} bytes",
+        MIN_KEY_LEN
+    );
+    let keys = std::iter::once(current)
+        .chain(previous.iter().copied())
+        .map(Key::derive)
+        .collect();
+    if KEYS.set(keys).is_err() {
+        panic!("cookie keys are already set");
+    }
+}
+
+fn keys() -> Result<&'static [Key]> {
+    KEYS.get().map(|keys| &keys[..]).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "cookie keys are not configured, see RustWeb::cookie_keys",
+        )
+    })
+}
+
+pub fn sign(name: &str, value: &str) -> Result<String> {
+    Ok(sign_with(&keys()?[0], name, value))
+}
+
+fn sign_with(key: &Key, name: &str, value: &str) -> String {
+    let tag = mac(&key.signing, name, value).finalize().into_bytes();
+    format!("{}.{}", URL_SAFE_NO_PAD.encode(tag), value)
+}
+
+// The value if the signature matches one of the keys
+pub fn verify(name: &str, signed: &str) -> Option<String> {
+    let (tag, value) = signed.split_once('.')?;
+    let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+    keys()
+        .ok()?
+        .iter()
+        .any(|key| mac(&key.signing, name, value).verify_slice(&tag).is_ok())
+        .then(|| value.to_string())
+}
+
+pub fn encrypt(name: &str, value: &str) -> Result<String> {
+    encrypt_with(&keys()?[0], name, value)
+}
+
+fn encrypt_with(key: &Key, name: &str, value: &str) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::fill(&mut nonce).map_err(|err| Error::other(err.to_string()))?;
+
+    let cipher = Aes256Gcm::new(&key.encryption.into());
+    let payload = Payload {
+        msg: value.as_bytes(),
+        aad: name.as_bytes(),
+    };
+    let sealed = cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| Error::other("cookie encryption failed"))?;
+
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&sealed);
+    Ok(URL_SAFE_NO_PAD.encode(data))
+}
+
+// The value if it decrypts and authenticates with one of the keys
+pub fn decrypt(name: &str, sealed: &str) -> Option<String> {
+    let data = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+    if data.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+
+    keys().ok()?.iter().find_map(|key| {
+        let cipher = Aes256Gcm::new(&key.encryption.into());
+        let payload = Payload {
+            msg: sealed,
+            aad: name.as_bytes(),
+        };
+        let value = cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(value).ok()
+    })
+}
+
+fn mac(key: &[u8; 32], name: &str, value: &str) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).unwrap();
+    mac.update(name.as_bytes());
+    mac.update(b"=");
+    mac.update(value.as_bytes());
+    mac
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURRENT: &[u8] = b"current key, at least thirty-two bytes";
+    const PREVIOUS: &[u8] = b"previous key, at least thirty-two bytes";
+    const UNKNOWN: &[u8] = b"some other key, at least thirty-two bytes";
+
+    // The keys are global, every test sets the same ones
+    fn init() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| set_keys(CURRENT, &[PREVIOUS]));
+    }
+
+    #[test]
+    fn signed() {
+        init();
+        let signed = sign("user", "ann").unwrap();
+        assert!(signed.ends_with(".ann"));
+        assert_eq!(verify("user", &signed).as_deref(), Some("ann"));
+
+        // Value, tag and name are all covered
+        let (tag, _) = signed.split_once('.').unwrap();
+        assert_eq!(verify("user", &format!("{}.bob", tag)), None);
+        assert_eq!(verify("admin", &signed), None);
+        let mut tampered = signed.into_bytes();
+        tampered[0] = if tampered[0] == b'A' { b'B' } else { b'A' };
+        assert_eq!(verify("user", &String::from_utf8(tampered).unwrap()), None);
+        assert_eq!(verify("user", "ann"), None);
+        assert_eq!(verify("user", "!!.ann"), None);
+    }
+
+    #[test]
+    fn signed_with_rotated_key() {
+        init();
+        let old = sign_with(&Key::derive(PREVIOUS), "user", "ann");
+        assert_eq!(verify("user", &old).as_deref(), Some("ann"));
+        // New values use the current key
+        assert_ne!(sign("user", "ann").unwrap(), old);
+
+        let unknown = sign_with(&Key::derive(UNKNOWN), "user", "ann");
+        assert_eq!(verify("user", &unknown), None);
+    }
+
+    #[test]
+    fn private() {
+        init();
+        let sealed = encrypt("cart", "3 apples").unwrap();
+        assert!(!sealed.contains("apples"));
+        assert_eq!(decrypt("cart", &sealed).as_deref(), Some("3 apples"));
+        // A new nonce every time
+        assert_ne!(encrypt("cart", "3 apples").unwrap(), sealed);
+
+        assert_eq!(decrypt("basket", &sealed), None);
+        let mut data = URL_SAFE_NO_PAD.decode(&sealed).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert_eq!(decrypt("cart", &URL_SAFE_NO_PAD.encode(&data)), None);
+        assert_eq!(
+            decrypt("cart", &URL_SAFE_NO_PAD.encode(&data[..NONCE_LEN])),
+            None
+        );
+        assert_eq!(decrypt("cart", "short"), None);
+        assert_eq!(decrypt("cart", "not base64!"), None);
+    }
+
+    #[test]
+    fn private_with_rotated_key() {
+        init();
+        let old = encrypt_with(&Key::derive(PREVIOUS), "cart", "3 apples").unwrap();
+        assert_eq!(decrypt("cart", &old).as_deref(), Some("3 apples"));
+
+        let unknown = encrypt_with(&Key::derive(UNKNOWN), "cart", "3 apples").unwrap();
+        assert_eq!(decrypt("cart", &unknown), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "cookie keys are already set")]
+    fn keys_are_set_once() {
+        init();
+        set_keys(CURRENT, &[]);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::context::Context;
+use crate::response::Response;
+use crate::thread_pool::ThreadPool;
+use crate::{handle_connection, Connection};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Result};
+use std::net::{self, SocketAddr};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/*
+Event driven connection layer.
+
+A single thread owns the listener and every connection that has not sent a complete
+request yet. Sockets are non-blocking, so a slow or idle client only costs a buffer
+instead of a whole worker. Once the request head has arrived (and the body too, as
+long as it is small), the socket is switched back to blocking mode and handed to the
+pool together with the bytes read so far, where the usual `Context`/handler path
+takes over.
+
+Once the response is written, a keep-alive connection goes back to the loop with
+whatever the client already sent after the request, and waits there for the next
+one. That takes a HTTP/1.1 request without `Connection: close` whose body was read
+to the end, answered by a sync handler. Other connections are closed.
+*/
+
+const LISTENER: Token = Token(0);
+// Woken when workers hand back connections
+const WAKER: Token = Token(1);
+
+// Largest request head we are willing to buffer
+const MAX_HEAD_SIZE: usize = 64 << 10; // 64KB
+
+// Bodies up to this size are read here, larger ones are streamed by the handler
+const MAX_BUFFERED_BODY: usize = 64 << 10; // 64KB
+
+// Connections that don't complete a request within this time are dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Kept-alive connections may wait this long for their next request
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+struct Pending {
+    stream: TcpStream,
+    address: SocketAddr,
+    buf: Vec<u8>,
+    // How much of `buf` was searched for the end of the head
+    scanned: usize,
+    last_active: Instant,
+    kept_alive: bool,
+}
+
+// Hands a connection back to the loop once its response is written
+#[derive(Clone)]
+pub(crate) struct KeepAlive {
+    sender: Sender<Connection>,
+    waker: Arc<Waker>,
+}
+
+enum Progress {
+    NeedMore,
+    Ready,
+    Closed,
+}
+
+pub fn run(listener: net::TcpListener, pool: ThreadPool) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    let (sender, returned) = mpsc::channel();
+    let keep_alive = KeepAlive {
+        sender,
+        waker: Arc::new(Waker::new(poll.registry(), WAKER)?),
+    };
+
+    let mut events = Events::with_capacity(1024);
+    let mut pending: HashMap<Token, Pending> = HashMap::new();
+    let mut next_token = WAKER.0 + 1;
+
+    loop {
+        if let Err(err) = poll.poll(&mut events, Some(Duration::from_secs(1))) {
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => loop {
+                    match listener.accept() {
+                        Ok((stream, address)) => {
+                            let token = Token(next_token);
+                            next_token += 1;
+                            let conn = Pending::new(stream, address, Vec::new(), false);
+                            register(&poll, &mut pending, token, conn);
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            println!("error occurred at accept: {}", err);
+                            break;
+                        }
+                    }
+                },
+
+                WAKER => {
+                    for conn in take_returned(&returned) {
+                        let token = Token(next_token);
+                        next_token += 1;
+                        register(&poll, &mut pending, token, conn);
+                        // Data that arrived in the meantime or was pipelined won't
+                        // raise another event
+                        advance(&poll, &mut pending, token, &pool, &keep_alive);
+                    }
+                }
+
+                token => advance(&poll, &mut pending, token, &pool, &keep_alive),
+            }
+        }
+
+        // Drop connections that went quiet before completing a request
+        let now = Instant::now();
+        pending.retain(|_, conn| {
+            let alive = now.duration_since(conn.last_active) < conn.timeout();
+            if !alive {
+                let _ = poll.registry().deregister(&mut conn.stream);
+            }
+            alive
+        });
+    }
+}
+
+fn register(poll: &Poll, pending: &mut HashMap<Token, Pending>, token: Token, mut conn: Pending) {
+    // Only this connection is lost, it's closed when dropped
+    match poll
+        .registry()
+        .register(&mut conn.stream, token, Interest::READABLE)
+    {
+        Ok(()) => {
+            pending.insert(token, conn);
+        }
+        Err(err) => println!("error occurred at register {}: {}", conn.address, err),
+    }
+}
+
+// Read what's there and dispatch the connection once it holds a complete request
+fn advance(
+    poll: &Poll,
+    pending: &mut HashMap<Token, Pending>,
+    token: Token,
+    pool: &ThreadPool,
+    keep_alive: &KeepAlive,
+) {
+    let progress = match pending.get_mut(&token) {
+        Some(conn) => conn.fill(),
+        None => return,
+    };
+
+    match progress {
+        Progress::NeedMore => (),
+        Progress::Closed => {
+            if let Some(mut conn) = pending.remove(&token) {
+                let _ = poll.registry().deregister(&mut conn.stream);
+            }
+        }
+        Progress::Ready => {
+            let mut conn = pending.remove(&token).unwrap();
+            match poll.registry().deregister(&mut conn.stream) {
+                Ok(()) => dispatch(conn, pool, keep_alive),
+                Err(err) => println!("error occurred at deregister {}: {}", conn.address, err),
+            }
+        }
+    }
+}
+
+// Connections handed back by workers, ready to be registered again
+fn take_returned(returned: &Receiver<Connection>) -> Vec<Pending> {
+    let mut conns = Vec::new();
+    for conn in returned.try_iter() {
+        if let Err(err) = conn.stream.set_nonblocking(true) {
+            println!("error occurred at keep-alive {}: {}", conn.address, err);
+            continue;
+        }
+        let stream = TcpStream::from_std(conn.stream);
+        conns.push(Pending::new(stream, conn.address, conn.buffered, true));
+    }
+    conns
+}
+
+fn dispatch(conn: Pending, pool: &ThreadPool, keep_alive: &KeepAlive) {
+    let stream: net::TcpStream = conn.stream.into();
+    if let Err(err) = stream.set_nonblocking(false) {
+        println!("error occurred at dispatch: {}", err);
+        return;
+    }
+
+    let connection = Connection {
+        address: conn.address,
+        stream,
+        buffered: conn.buf,
+        keep_alive: Some(keep_alive.clone()),
+    };
+
+    pool.excute(move || {
+        handle_connection(connection);
+    });
+}
+
+// Called once the response to the request of `context` is written. The connection
+// goes back to the loop if it can carry another request, see above.
+pub(crate) fn reuse(context: &mut Context, response: &Response) {
+    let keep_alive = match context.keep_alive.take() {
+        Some(keep_alive) => keep_alive,
+        None => return,
+    };
+    let request = &context.request;
+    let closes = |value: &str| {
+        value
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("close"))
+    };
+    if request.version != "HTTP/1.1"
+        || !request.body_finished()
+        || closes(&request.header_first("Connection"))
+        || response.header().get("Connection").is_some_and(closes)
+        // A body sent anyway would be taken for the next response
+        || (request.method == "HEAD" && response.has_body())
+    {
+        return;
+    }
+
+    let stream = match context.stream.try_clone() {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+    // Pipelined requests that were read already
+    let mut buffered = context.request.reader.buffer().to_vec();
+    buffered.extend_from_slice(context.request.reader.get_ref().pending());
+    let conn = Connection {
+        address: context.request.address,
+        stream,
+        buffered,
+        keep_alive: None,
+    };
+    if keep_alive.sender.send(conn).is_ok() {
+        let _ = keep_alive.waker.wake();
+    }
+}
+
+impl Pending {
+    fn new(stream: TcpStream, address: SocketAddr, buf: Vec<u8>, kept_alive: bool) -> Self {
+        Self {
+            stream,
+            address,
+            buf,
+            scanned: 0,
+            last_active: Instant::now(),
+            kept_alive,
+        }
+    }
+
+    // Read everything currently available and report whether a request is complete
+    fn fill(&mut self) -> Progress {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Progress::Closed,
+                Ok(n) => {
+                    self.buf.extend_from_slice(&buf[..n]);
+                    self.last_active = Instant::now();
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return Progress::Closed,
+            }
+        }
+
+        match find_head_end(&self.buf, self.scanned) {
+            Some(head_end) => {
+                let content_length = parse_content_length(&self.buf[..head_end]);
+                let body_read = self.buf.len() - head_end;
+                if body_read >= content_length || body_read >= MAX_BUFFERED_BODY {
+                    Progress::Ready
+                } else {
+                    Progress::NeedMore
+                }
+            }
+            None if self.buf.len() > MAX_HEAD_SIZE => Progress::Closed,
+            None => {
+                self.scanned = self.buf.len();
+                Progress::NeedMore
+            }
+        }
+    }
+
+    // A kept-alive connection may wait longer for its next request than a started
+    // one may take to complete it
+    fn timeout(&self) -> Duration {
+        if self.kept_alive && self.buf.is_empty() {
+            KEEP_ALIVE_TIMEOUT
+        } else {
+            IDLE_TIMEOUT
+        }
+    }
+}
+
+// Position right after the blank line that terminates the request head. The
+// first `scanned` bytes were searched before, only a blank line starting in their
+// last two bytes may have been cut off there.
+fn find_head_end(buf: &[u8], scanned: usize) -> Option<usize> {
+    let start = scanned.saturating_sub(2);
+    buf[start..]
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c == b'\n')
+        .find_map(|(i, _)| match &buf[start + i + 1..] {
+            [b'\n', ..] => Some(start + i + 2),
+            [b'\r', b'\n', ..] => Some(start + i + 3),
+            _ => None,
+        })
+}
+
+fn parse_content_length(head: &[u8]) -> usize {
+    String::from_utf8_lossy(head)
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, v)| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextFn;
+    use crate::header::HeaderMap;
+    use crate::response::status::Status;
+    use crate::response::NoneContent;
+
+    #[test]
+    fn head_end() {
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody";
+        assert_eq!(find_head_end(head, 0), Some(27));
+        assert_eq!(
+            find_head_end(b"GET / HTTP/1.1\nHost: a\n\nbody", 0),
+            Some(24)
+        );
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n", 0), None);
+        assert_eq!(find_head_end(b"", 0), None);
+
+        // Fed byte by byte, resuming where the last search stopped
+        for input in [
+            &head[..],
+            b"GET / HTTP/1.1\nA: b\n\n",
+            b"GET / HTTP/1.1\r\n\n",
+        ] {
+            let expected = find_head_end(input, 0);
+            let mut scanned = 0;
+            let mut found = None;
+            for end in 1..=input.len() {
+                found = find_head_end(&input[..end], scanned);
+                if found.is_some() {
+                    break;
+                }
+                scanned = end;
+            }
+            assert_eq!(found, expected, "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn content_length() {
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\ncontent-LENGTH:  42 \r\n\r\n";
+        assert_eq!(parse_content_length(head), 42);
+        assert_eq!(
+            parse_content_length(b"POST / HTTP/1.1\nContent-Length: 7\n\n"),
+            7
+        );
+        assert_eq!(
+            parse_content_length(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            0
+        );
+        assert_eq!(
+            parse_content_length(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"),
+            0
+        );
+        // Not on the request line
+        assert_eq!(parse_content_length(b"Content-Length: 5\r\n\r\n"), 0);
+    }
+
+    // Answer the request in `raw` with `respond` (twice), what's handed back to the loop
+    fn answer(raw: &str, respond: fn(&mut Context)) -> Option<Connection> {
+        let poll = Poll::new().unwrap();
+        let (sender, returned) = mpsc::channel();
+        let keep_alive = KeepAlive {
+            sender,
+            waker: Arc::new(Waker::new(poll.registry(), WAKER).unwrap()),
+        };
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        let mut context = Context::new(Connection {
+            address,
+            stream,
+            buffered: raw.as_bytes().to_vec(),
+            keep_alive: Some(keep_alive),
+        })
+        .unwrap();
+        respond(&mut context);
+        respond(&mut context);
+        let conn = returned.try_recv().ok();
+        assert!(returned.try_recv().is_err(), "handed back twice");
+        conn
+    }
+
+    fn ok(c: &mut Context) {
+        c.error_with_status(Status::OK);
+    }
+
+    #[test]
+    fn keeps_finished_connections() {
+        let conn = answer("GET / HTTP/1.1\r\n\r\n", ok).unwrap();
+        assert!(conn.buffered.is_empty());
+
+        // Pipelined requests stay with the connection
+        let conn = answer(
+            "POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /next HTTP/1.1\r\n\r\n",
+            |c| {
+                assert_eq!(c.request.body().unwrap(), b"abc");
+                ok(c)
+            },
+        )
+        .unwrap();
+        assert_eq!(conn.buffered, b"GET /next HTTP/1.1\r\n\r\n");
+
+        let head = |c: &mut Context| {
+            let response = Response::new(Status::OK, HeaderMap::new(), Box::new(NoneContent));
+            c.respond(response);
+        };
+        assert!(answer("HEAD / HTTP/1.1\r\n\r\n", head).is_some());
+    }
+
+    #[test]
+    fn closes_others() {
+        for raw in [
+            "GET / HTTP/1.0\r\n\r\n",
+            "GET / HTTP/1.1\r\nConnection: Upgrade, close\r\n\r\n",
+            // The body is still on the way
+            "POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        ] {
+            assert!(answer(raw, ok).is_none(), "{:?}", raw);
+        }
+
+        let close = |c: &mut Context| {
+            c.add_header("Connection", "close").unwrap();
+            ok(c)
+        };
+        assert!(answer("GET / HTTP/1.1\r\n\r\n", close).is_none());
+        let body = |c: &mut Context| c.json_raw(b"{}");
+        assert!(answer("HEAD / HTTP/1.1\r\n\r\n", body).is_none());
+        assert!(answer("GET / HTTP/1.1\r\n\r\n", body).is_some());
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{Error, ErrorKind, Result};
+
+/*
+HTTP header fields, shared by requests and responses.
+
+Names are matched case-insensitively ("Content-Length" == "content-length") but kept
+as given for output. A name may occur several times (e.g. one `Set-Cookie` per
+cookie) and fields keep the order they were added in.
+
+Names must be tokens and values must not contain CR, LF or NUL, so a value taken
+from user input can't inject extra header lines.
+*/
+#[derive(Debug, Default, Clone)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    // Add a field, keeping existing fields of the same name
+    pub fn append(&mut self, name: &str, value: &str) -> Result<()> {
+        validate(name, value)?;
+        self.entries.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    // Replace all fields of the same name with this one
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<()> {
+        validate(name, value)?;
+        match self.position(name) {
+            Some(i) => {
+                // Keep the position of the first field, drop the others
+                self.entries[i].1 = value.to_string();
+                let mut j = i + 1;
+                while j < self.entries.len() {
+                    if self.entries[j].0.eq_ignore_ascii_case(name) {
+                        self.entries.remove(j);
+                    } else {
+                        j += 1;
+                    }
+                }
+            }
+            None => self.entries.push((name.to_string(), value.to_string())),
+        }
+        Ok(())
+    }
+
+    // The first value of the field
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.position(name).map(|i| self.entries[i].1.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(name))
+    }
+}
+
+fn validate(name: &str, value: &str) -> Result<()> {
+    if name.is_empty() || !name.bytes().all(is_token) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid header name: {:?}", name),
+        ));
+    }
+    if value.bytes().any(|c| c == b'\r' || c == b'\n' || c == 0) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value for header {}", name),
+        ));
+    }
+    Ok(())
+}
+
+// tchar from RFC 9110
+pub(crate) fn is_token(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let mut map = HeaderMap::new();
+        for name in ["", "X Header", "X-A:b", "X-A\r\n", "Ünicode", "(x)", "a\"b"] {
+            let err = map.append(name, "v").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", name);
+            assert!(map.insert(name, "v").is_err(), "{:?}", name);
+        }
+        for value in ["a\r\nSet-Cookie: x=1", "a\rb", "a\nb", "a\0b"] {
+            assert!(map.append("X-Value", value).is_err(), "{:?}", value);
+            assert!(map.insert("X-Value", value).is_err(), "{:?}", value);
+        }
+        assert!(map.is_empty());
+
+        // Spaces, quotes and non-ASCII are fine in values
+        map.append("X-Ok", "a \"b\", c; é").unwrap();
+        map.append("!#$%&'*+-.^_`|~09aZ", "").unwrap();
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn case_insensitive_and_ordered() {
+        let mut map = HeaderMap::new();
+        map.append("Set-Cookie", "a=1").unwrap();
+        map.append("Content-Type", "text/plain").unwrap();
+        map.append("set-cookie", "b=2").unwrap();
+        map.append("X-Other", "x").unwrap();
+
+        assert_eq!(map.get("SET-COOKIE"), Some("a=1"));
+        assert_eq!(map.get_all("Set-Cookie"), ["a=1", "b=2"]);
+        assert!(map.contains_key("content-type"));
+        assert_eq!(map.get("Missing"), None);
+        // Names are written as given
+        let fields: Vec<(&str, &str)> = map.iter().collect();
+        assert_eq!(
+            fields,
+            [
+                ("Set-Cookie", "a=1"),
+                ("Content-Type", "text/plain"),
+                ("set-cookie", "b=2"),
+                ("X-Other", "x"),
+            ]
+        );
+
+        // Replaces in place of the first field and drops the rest
+        map.insert("SET-cookie", "c=3").unwrap();
+        let fields: Vec<(&str, &str)> = map.iter().collect();
+        assert_eq!(
+            fields,
+            [
+                ("Set-Cookie", "c=3"),
+                ("Content-Type", "text/plain"),
+                ("X-Other", "x"),
+            ]
+        );
+
+        map.remove("CONTENT-TYPE");
+        assert_eq!(map.len(), 2);
+        assert!(!map.contains_key("Content-Type"));
+    }
+}
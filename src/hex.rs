@@ -0,0 +1,18 @@
+// Lowercase hex, two digits per byte
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// `len` random bytes as hex, for ids, boundaries and file names.
+// `what` names the use in the panic when the system has no random source.
+pub fn random(len: usize, what: &str) -> String {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).unwrap_or_else(|_| panic!("no random source for {}", what));
+    encode(&bytes)
+}
+
+// Whether `id` has the format of `random(len, ..)`. Ids we issued come back from
+// clients and end up in file names, anything else is rejected.
+pub fn is_random(id: &str, len: usize) -> bool {
+    id.len() == len * 2 && id.bytes().all(|c| c.is_ascii_hexdigit())
+}
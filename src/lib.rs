@@ -1,65 +1,221 @@
 use crate::response::status::Status;
 use context::{Context, ContextFn};
+pub use request::digest::BodyDigest;
+pub use request::extract::ExtractError;
+pub use request::json::JsonError;
+pub use request::limits::{LimitError, MultipartLimits};
+pub use request::mime::formdata::FormFile;
+pub use request::progress::UploadProgress;
+pub use request::upload::{SavedBody, TempFile};
+#[cfg(feature = "async")]
+use response::IntoResponse;
+use router::Handler;
+use session::SessionStore;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
 use thread_pool::ThreadPool;
+use tus::TusConfig;
 
 mod content_type;
 pub mod context;
+pub mod cookie;
+#[cfg(feature = "event-loop")]
+mod event_loop;
+pub mod header;
+mod hex;
 mod request;
 pub mod response;
 mod router;
+#[cfg(feature = "async")]
+mod runtime;
+pub mod session;
+mod stream;
 mod thread_pool;
+pub mod tus;
+
+// Routes whose handler takes care of the method itself
+const ANY_METHOD: &str = "*";
 
 pub struct RustWeb {
     address: String,
     port: u32,
+    min_workers: usize,
+    max_workers: usize,
+    worker_keep_alive: Duration,
 }
 
 pub struct Connection {
     address: SocketAddr,
     stream: TcpStream,
+    // Request bytes already read from the stream
+    buffered: Vec<u8>,
+    // Set by the event loop, which takes the connection back after the response
+    #[cfg(feature = "event-loop")]
+    keep_alive: Option<event_loop::KeepAlive>,
 }
 
 pub fn build_server(address: &str, port: u32) -> RustWeb {
     RustWeb {
         address: address.to_string(),
         port,
+        min_workers: 4,
+        max_workers: 64,
+        worker_keep_alive: Duration::from_secs(60),
     }
 }
 
 impl RustWeb {
-    pub fn get(&self, path: &str, handle_func: fn(Context)) {
-        let item = router::RoutingItem {
-            path: path.to_string(),
-            method: "GET".to_string(),
-            func: handle_func,
-        };
+    // The pool keeps `min` workers alive and grows up to `max` under load
+    pub fn workers(&mut self, min: usize, max: usize) {
+        assert!(min > 0 && max >= min);
+        self.min_workers = min;
+        self.max_workers = max;
+    }
 
-        router::insert(path, item);
+    // How long an extra worker may stay idle before it shuts down
+    pub fn worker_keep_alive(&mut self, keep_alive: Duration) {
+        self.worker_keep_alive = keep_alive;
+    }
+
+    pub fn get(&self, path: &str, handle_func: fn(Context)) {
+        self.route(path, "GET", Handler::Sync(handle_func));
     }
 
     pub fn post(&self, path: &str, handle_func: fn(Context)) {
+        self.route(path, "POST", Handler::Sync(handle_func));
+    }
+
+    // Async handlers run on a shared runtime, so awaiting doesn't hold a pool worker.
+    // The returned value is written back as the response.
+    #[cfg(feature = "async")]
+    pub fn get_async<F, Fut, R>(&self, path: &str, handle_func: F)
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoResponse,
+    {
+        self.route(path, "GET", runtime::handler(handle_func));
+    }
+
+    #[cfg(feature = "async")]
+    pub fn post_async<F, Fut, R>(&self, path: &str, handle_func: F)
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoResponse,
+    {
+        self.route(path, "POST", runtime::handler(handle_func));
+    }
+
+    // Largest request body accepted, larger ones are answered with 413 before the
+    // handler runs. Defaults to 10MB.
+    pub fn body_limit(&self, limit: usize) {
+        request::set_body_limit(limit);
+    }
+
+    // Body limit for one route, e.g. large uploads or small JSON APIs
+    pub fn route_body_limit(&self, path: &str, limit: usize) {
+        router::set_body_limit(path, limit);
+    }
+
+    // Limits for the parts of multipart bodies, see `MultipartLimits`
+    pub fn multipart_limits(&self, limits: MultipartLimits) {
+        request::limits::set_multipart_limits(limits);
+    }
+
+    pub fn route_multipart_limits(&self, path: &str, limits: MultipartLimits) {
+        router::set_multipart_limits(path, limits);
+    }
+
+    // Serve the progress of uploads sending an upload id at `path`, see `UploadProgress`
+    pub fn upload_progress(&self, path: &str) {
+        request::progress::enable();
+        self.get(path, request::progress::handler);
+    }
+
+    // Called as upload bodies with an upload id are read
+    pub fn on_upload_progress(&self, hook: fn(&str, &UploadProgress)) {
+        request::progress::set_hook(hook);
+    }
+
+    // Resumable uploads at `path` and `path/:id`, see `tus`
+    pub fn tus(&self, path: &str, config: TusConfig) {
+        let (path, max_size) = tus::set_config(path, config);
+        let upload_path = format!("{}/:id", path);
+        self.route(&path, ANY_METHOD, Handler::Sync(tus::handler));
+        self.route(&upload_path, ANY_METHOD, Handler::Sync(tus::handler));
+        router::set_body_limit(&upload_path, max_size);
+    }
+
+    // Where `spool_to_tempfile` puts request bodies, the system temp dir by default
+    pub fn upload_dir(&self, dir: &str) {
+        request::upload::set_upload_dir(dir);
+    }
+
+    // Keep session data in `store`, handlers reach it through `c.session()`.
+    // Without this, sessions are kept in memory and expire after 30 idle minutes.
+    pub fn sessions<S: SessionStore + 'static>(&self, store: S) {
+        session::set_store(Box::new(store));
+    }
+
+    // Server keys for signed and private cookies, at least 32 random bytes each.
+    // Cookies are made with `current`, the `previous` keys are still accepted.
+    pub fn cookie_keys(&self, current: &[u8], previous: &[&[u8]]) {
+        cookie::secure::set_keys(current, previous);
+    }
+
+    fn route(&self, path: &str, method: &str, handler: Handler) {
         let item = router::RoutingItem {
             path: path.to_string(),
-            method: "POST".to_string(),
-            func: handle_func,
+            method: method.to_string(),
+            handler,
+            body_limit: None,
+            multipart_limits: None,
         };
         router::insert(path, item);
     }
 
     pub fn run(&self) {
-        let pool = ThreadPool::new(4);
-        let listener = TcpListener::bind(format!("{}:{}", self.address, self.port)).unwrap();
+        let pool = self.build_pool();
+        let listener = self.bind();
 
         // for stream in listener.incoming() {}
         while let Ok((stream, address)) = listener.accept() {
-            let connection = Connection { stream, address };
+            let connection = Connection {
+                stream,
+                address,
+                buffered: vec![],
+                #[cfg(feature = "event-loop")]
+                keep_alive: None,
+            };
 
             pool.excute(move || {
                 handle_connection(connection);
             });
         }
     }
+
+    // Like `run`, but connections are read by a single non-blocking event loop and
+    // only complete requests are handed to the worker pool
+    #[cfg(feature = "event-loop")]
+    pub fn run_event_loop(&self) {
+        let pool = self.build_pool();
+        let listener = self.bind();
+
+        if let Err(err) = event_loop::run(listener, pool) {
+            println!("error occurred at run_event_loop: {}", err);
+        }
+    }
+
+    fn build_pool(&self) -> ThreadPool {
+        ThreadPool::new(self.min_workers, self.max_workers, self.worker_keep_alive)
+    }
+
+    fn bind(&self) -> TcpListener {
+        TcpListener::bind(format!("{}:{}", self.address, self.port)).unwrap()
+    }
 }
 
 fn handle_connection(conn: Connection) {
@@ -71,13 +227,29 @@ fn handle_connection(conn: Connection) {
         Ok(mut context) => {
             let key = &context.request.path[..];
 
-            if let Some(mapping) = router::find(key) {
-                if context.request.method != mapping.method {
+            if let Some((mapping, params)) = router::find(key) {
+                if mapping.method != ANY_METHOD && context.request.method != mapping.method {
                     context.error_with_status(Status::MethodNotAllowed);
                     return;
                 }
-                let f = mapping.func;
-                f(context);
+                context.request.params = params;
+
+                if let Some(limit) = mapping.body_limit {
+                    context.request.body_limit = limit;
+                }
+                if let Some(limits) = &mapping.multipart_limits {
+                    context.request.multipart_limits = limits.clone();
+                }
+                if context.request.content_length > context.request.body_limit {
+                    context.error_with_status(Status::ContentTooLarge);
+                    return;
+                }
+
+                match &mapping.handler {
+                    Handler::Sync(f) => f(context),
+                    #[cfg(feature = "async")]
+                    Handler::Async(f) => runtime::spawn(f, context),
+                }
             } else {
                 context.error();
             }
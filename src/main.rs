@@ -1,4 +1,6 @@
 use rustweb::context::{Context, ContextFn};
+use rustweb::response::status::Status;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::PathBuf;
@@ -13,35 +15,57 @@ fn main() {
     web.post("/update", update_handler);
     web.post("/file/upload", upload_handler);
     web.post("/file/multipart", multipart_handler);
+    web.route_body_limit("/file/upload", 2 << 30); // 2GB
 
     web.run();
 }
 
+#[derive(Serialize)]
+struct Reply {
+    code: u16,
+    message: String,
+}
+
 fn hello_handler(mut c: Context) {
-    let content = "{\"code\":200,\"message\":\"\"}";
-    c.json(content.as_bytes());
+    c.json(&Reply {
+        code: 200,
+        message: "".to_string(),
+    });
 }
 
 fn update_handler(mut c: Context) {
-    let content = "{\"code\":200,\"message\":\"\"}";
     // let body = c.request.body();
     // println!("\n{}", String::from_utf8(body).unwrap());
     // println!("body: len = {}",body.len());
-    c.request.parse_post_form();
+    if c.request.parse_post_form().is_err() {
+        c.error_with_status(Status::BadRequest);
+        return;
+    }
     println!("form: {:?}", c.request.post_form);
-    c.json(content.as_bytes());
+    c.json(&Reply {
+        code: 200,
+        message: "".to_string(),
+    });
+}
+
+#[derive(Deserialize)]
+struct ApkQuery {
+    brand: String,
+    version: Option<String>,
+    filename: String,
 }
 
 // http://127.0.0.1:7878/file/apk?brand=gwm&version=1609&filename=wp1609.apk
 fn apk_handler(mut c: Context) {
     let parent_dir = "/Users/sfdex/File/apk";
-    let brand = c.request.query("brand");
-    let version = c.request.query("version");
-    let filename = c.request.query("filename");
-    if brand.is_empty() || filename.is_empty() {
-        c.error();
-        return;
-    }
+    let ApkQuery {
+        brand,
+        version,
+        filename,
+    } = match c.request.query_as() {
+        Ok(query) => query,
+        Err(err) => return c.respond(err),
+    };
     
     /*let path = format!("{}/{}/{}", parent_dir, brand,
         if version.is_empty() {
@@ -53,7 +77,7 @@ fn apk_handler(mut c: Context) {
 
     let mut path = PathBuf::from(parent_dir);
     path.push(brand);
-    if !version.is_empty() { path.push(version)  }
+    if let Some(version) = version { path.push(version)  }
     path.push(&filename);
 
     let file_result = File::open(&path);
@@ -66,60 +90,47 @@ fn apk_handler(mut c: Context) {
 }
 
 fn upload_handler(mut c: Context) {
-    let mut buf = vec![0; 8192];
-    let mut file = File::create("test.img").unwrap();
-
-    let mut total = 0usize;
-    let length: usize = c.request.header_first("Content-Length").parse().unwrap();
-
-    loop {
-        match c.request.read_body(&mut buf) {
-            Ok(0) => {
-                println!("End");
-                break;
-            }
-            Ok(n) => {
-                file.write_all(&buf[0..n]).unwrap();
-                total += n;
-                println!("length = {length}, total = {total}, n = {n}");
-                if total >= length {
-                    println!("last: {:?}", &buf[n - 300..n]);
-                    println!("Finish");
-                    break;
-                }
-            }
-            Err(e) => {
-                println!("upload_handler error: {}", e);
-                break;
-            }
+    match c.request.save_body_to("test.img") {
+        Ok(saved) => println!("Recv file size: {}, sha256: {}", saved.size, saved.sha256),
+        Err(e) => {
+            println!("upload_handler error: {}", e);
+            return c.respond(e);
         }
     }
-    file.flush().unwrap();
-    let metadata = file.metadata().unwrap();
-    println!("Recv file size: {}", metadata.len());
-    let content = "{\"code\":200,\"message\":\"Upload finish!\"}";
-    c.json(content.as_bytes());
+    c.json(&Reply {
+        code: 200,
+        message: "Upload finish!".to_string(),
+    });
 }
 
 fn multipart_handler(mut c: Context) {
-    let reader = c.request.multipart();
-
-    loop {
-        let part = reader.next();
-        if let None = part {
-            break;
-        }
-        let part = part.unwrap();
-        println!("disposition: {}", part.disposition);
+    for part in c.request.multipart() {
+        let mut part = match part {
+            Ok(part) => part,
+            Err(e) => {
+                println!("multipart_handler error: {}", e);
+                return c.respond(e);
+            }
+        };
+        println!("name: {:?}, filename: {:?}", part.name(), part.filename());
         println!("contentType: {:?}", part.content_type);
 
-        let body = reader.part_body().unwrap();
-        println!("body: {}", String::from_utf8(body.to_vec()).unwrap());
+        let mut body = Vec::new();
+        if let Err(e) = part.read_to_end(&mut body) {
+            return c.respond(e);
+        }
+        println!("body: {}", String::from_utf8_lossy(&body));
     }
 
     c.ok();
 }
 
+#[derive(Serialize)]
+struct IpReply {
+    code: u16,
+    ip: String,
+}
+
 fn ip_handler(mut c: Context) {
     let x_real_ip = c.request.header_first("X-Real-IP");
     let ip = if !x_real_ip.is_empty() {
@@ -127,6 +138,5 @@ fn ip_handler(mut c: Context) {
     } else {
         c.request.address.ip().to_string()
     };
-    let content = format!("{{\"code\":200,\"ip\":\"{}\"}}", ip);
-    c.json(content.as_bytes());
+    c.json(&IpReply { code: 200, ip });
 }
@@ -0,0 +1,205 @@
+use super::body::{check_chunk_end, chunk_line, parse_chunk_size, MAX_CHUNK_LINE};
+use super::limits::LimitError;
+use super::Request;
+use crate::stream::AsyncStream;
+use std::io::{Error, ErrorKind, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+
+/*
+The request body for async handlers. Once the head is read, the connection is
+registered with the runtime and the body is read from there, so a handler waiting
+for a slow client doesn't hold a runtime thread.
+
+    web.post_async("/echo", |mut c: rustweb::Context| async move {
+        c.request.body_async().await
+    });
+
+Framing, limits, progress and digests are the same as for `body_reader`. The
+blocking readers (`body`, `read_body`, `body_reader`, `multipart`) fail with
+`ErrorKind::Unsupported` in async handlers until the body was read here; after
+`body_async`, `json` and `parse_post_form` work on the kept body.
+*/
+impl Request {
+    // Continue reading from `read`, starting with what was already buffered
+    pub(crate) fn start_async(&mut self, read: OwnedReadHalf) {
+        let mut pending = self.reader.buffer().to_vec();
+        pending.extend_from_slice(self.reader.get_ref().pending());
+        self.async_reader = Some(BufReader::new(AsyncStream::new(pending, read)));
+    }
+
+    // The whole body, kept for later calls, like `body`
+    pub async fn body_async(&mut self) -> Result<Vec<u8>> {
+        if self.content_length > self.body_limit {
+            return Err(LimitError::BodyTooLarge {
+                limit: self.body_limit,
+            }
+            .into());
+        }
+
+        if !self.body.is_empty() {
+            return Ok(self.body.to_vec());
+        }
+
+        let mut body = Vec::new();
+        let mut buf = vec![0u8; 8 * 1024];
+        loop {
+            match self.read_body_async(&mut buf).await? {
+                0 => break,
+                n => body.extend_from_slice(&buf[..n]),
+            }
+        }
+        self.body = body;
+
+        Ok(self.body.to_vec())
+    }
+
+    // Read the next piece of the body, 0 at its end, like `read_body`
+    pub async fn read_body_async(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.body_done {
+            self.digests.check()?;
+            return Ok(0);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = if self.chunked {
+            self.read_chunked_async(buf).await?
+        } else {
+            self.read_sized_async(buf).await?
+        };
+        self.account(&buf[..n])?;
+        Ok(n)
+    }
+
+    async fn read_sized_async(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let max = match self.sized_max(buf.len()) {
+            Some(max) => max,
+            None => return Ok(0),
+        };
+        let n = self.async_reader()?.read(&mut buf[..max]).await?;
+        self.sized_read(n)
+    }
+
+    async fn read_chunked_async(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.chunk_remaining == 0 {
+            let size = parse_chunk_size(&self.read_chunk_line_async().await?)?;
+            if size == 0 {
+                // Trailer fields after the last chunk are skipped
+                while !self.read_chunk_line_async().await?.is_empty() {}
+                self.body_done = true;
+                return Ok(0);
+            }
+            self.chunk_remaining = size;
+        }
+
+        let max = buf.len().min(self.chunk_remaining);
+        let n = self.async_reader()?.read(&mut buf[..max]).await?;
+        if self.chunk_read(n)? {
+            check_chunk_end(&self.read_chunk_line_async().await?)?;
+        }
+        Ok(n)
+    }
+
+    async fn read_chunk_line_async(&mut self) -> Result<String> {
+        let mut line = String::new();
+        let n = self
+            .async_reader()?
+            .take(MAX_CHUNK_LINE)
+            .read_line(&mut line)
+            .await?;
+        chunk_line(line, n)
+    }
+
+    fn async_reader(&mut self) -> Result<&mut BufReader<AsyncStream>> {
+        self.async_reader.as_mut().ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                "only async handlers read the body with body_async or read_body_async",
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::Context;
+    use crate::runtime::{into_async, runtime};
+    use crate::Connection;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    // A context taken over by the runtime with `buffered` already read off the
+    // socket and `sent` still on it
+    fn async_context(buffered: &[u8], sent: &[u8]) -> Context {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        client.write_all(sent).unwrap();
+        drop(client);
+        let mut context = Context::new(Connection {
+            address,
+            stream,
+            buffered: buffered.to_vec(),
+            #[cfg(feature = "event-loop")]
+            keep_alive: None,
+        })
+        .unwrap();
+        into_async(&mut context).unwrap();
+        context
+    }
+
+    #[test]
+    fn sized_body_continues_after_buffered_bytes() {
+        let mut context = async_context(
+            b"POST /echo HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello",
+            b" world",
+        );
+        let body = runtime().block_on(context.request.body_async()).unwrap();
+        assert_eq!(body, b"hello world");
+        // Kept for the blocking readers too
+        assert_eq!(context.request.body().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn chunked_body() {
+        let mut context = async_context(
+            b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+            b"lo\r\n6;ext=1\r\n world\r\n0\r\nTrailer: x\r\n\r\n",
+        );
+        let body = runtime().block_on(context.request.body_async()).unwrap();
+        assert_eq!(body, b"hello world");
+    }
+
+    #[test]
+    fn truncated_and_too_large_bodies() {
+        let mut context = async_context(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello", b"");
+        let err = runtime()
+            .block_on(context.request.body_async())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let mut chunked = async_context(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"b\r\nhello world\r\n0\r\n\r\n",
+        );
+        chunked.request.body_limit = 5;
+        let err = runtime()
+            .block_on(chunked.request.body_async())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn blocking_reads_are_refused() {
+        let mut context = async_context(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", b"");
+        let mut body = Vec::new();
+        let err = context
+            .request
+            .body_reader()
+            .read_to_end(&mut body)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,307 @@
+use super::limits::LimitError;
+use super::progress::{self, UploadProgress};
+use super::Request;
+use std::io::{prelude::*, Error, ErrorKind, Result};
+
+/*
+The request body as a stream, framed by `Content-Length` or by
+`Transfer-Encoding: chunked`. Reading stops exactly at the end of the body, so a
+pipelined request behind it is left alone.
+
+    let mut file = File::create("upload.bin")?;
+    io::copy(&mut c.request.body_reader(), &mut file)?;
+
+A connection closed before the body is complete fails with
+`ErrorKind::UnexpectedEof`, a body larger than `body_limit` or not matching the
+digest the client sent (see `digest`) with `ErrorKind::InvalidData`.
+*/
+pub struct BodyReader<'a> {
+    request: &'a mut Request,
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.request.read_framed(buf)
+    }
+}
+
+// Longest chunk size line accepted, extensions included
+pub(crate) const MAX_CHUNK_LINE: u64 = 4096;
+
+impl Request {
+    pub fn body_reader(&mut self) -> BodyReader<'_> {
+        BodyReader { request: self }
+    }
+
+    // Whether the whole body was read, so the next request comes next
+    #[cfg(feature = "event-loop")]
+    pub(crate) fn body_finished(&self) -> bool {
+        self.body_done || (!self.chunked && self.body_read == self.content_length)
+    }
+
+    pub(crate) fn read_framed(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.body_done {
+            self.digests.check()?;
+            return Ok(0);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        #[cfg(feature = "async")]
+        if self.async_reader.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "async handlers read the body with body_async or read_body_async",
+            ));
+        }
+
+        let n = if self.chunked {
+            self.read_chunked(buf)?
+        } else {
+            self.read_sized(buf)?
+        };
+        self.account(&buf[..n])?;
+        Ok(n)
+    }
+
+    // Check what was just read of the body against the limit, report the progress
+    // and hash it
+    pub(crate) fn account(&mut self, data: &[u8]) -> Result<()> {
+        self.body_read += data.len();
+        if self.body_read > self.body_limit {
+            return Err(LimitError::BodyTooLarge {
+                limit: self.body_limit,
+            }
+            .into());
+        }
+
+        if let Some(id) = &self.upload_id {
+            let progress = UploadProgress {
+                received: self.body_read as u64,
+                total: (!self.chunked).then_some(self.content_length as u64),
+                done: self.body_done,
+            };
+            progress::update(id, progress);
+        }
+
+        self.digests.update(data);
+        if self.body_done {
+            self.digests.finish()?;
+        }
+        Ok(())
+    }
+
+    fn read_sized(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let max = match self.sized_max(buf.len()) {
+            Some(max) => max,
+            None => return Ok(0),
+        };
+        let n = self.reader.read(&mut buf[..max])?;
+        self.sized_read(n)
+    }
+
+    // How much to read of a body with Content-Length, None at its end
+    pub(crate) fn sized_max(&mut self, len: usize) -> Option<usize> {
+        let remaining = self.content_length - self.body_read;
+        if remaining == 0 {
+            self.body_done = true;
+            return None;
+        }
+        Some(len.min(remaining))
+    }
+
+    // Nothing read before the end means the body was cut off
+    pub(crate) fn sized_read(&self, n: usize) -> Result<usize> {
+        match n {
+            0 => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "body truncated after {} of {} bytes",
+                    self.body_read, self.content_length
+                ),
+            )),
+            n => Ok(n),
+        }
+    }
+
+    fn read_chunked(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.chunk_remaining == 0 {
+            let size = parse_chunk_size(&self.read_chunk_line()?)?;
+            if size == 0 {
+                self.read_trailers()?;
+                self.body_done = true;
+                return Ok(0);
+            }
+            self.chunk_remaining = size;
+        }
+
+        let max = buf.len().min(self.chunk_remaining);
+        let n = self.reader.read(&mut buf[..max])?;
+        if self.chunk_read(n)? {
+            check_chunk_end(&self.read_chunk_line()?)?;
+        }
+        Ok(n)
+    }
+
+    // Count `n` bytes read of the current chunk, true if that was its end
+    pub(crate) fn chunk_read(&mut self, n: usize) -> Result<bool> {
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "chunk truncated"));
+        }
+        self.chunk_remaining -= n;
+        Ok(self.chunk_remaining == 0)
+    }
+
+    // Trailer fields after the last chunk are skipped
+    fn read_trailers(&mut self) -> Result<()> {
+        while !self.read_chunk_line()?.is_empty() {}
+        Ok(())
+    }
+
+    fn read_chunk_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        let n = (&mut self.reader)
+            .take(MAX_CHUNK_LINE)
+            .read_line(&mut line)?;
+        chunk_line(line, n)
+    }
+}
+
+// 1a;name=value
+pub(crate) fn parse_chunk_size(line: &str) -> Result<usize> {
+    let size = line.split(';').next().unwrap_or("").trim();
+    usize::from_str_radix(size, 16).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid chunk size: {:?}", size),
+        )
+    })
+}
+
+// The data of every chunk is followed by a line break
+pub(crate) fn check_chunk_end(line: &str) -> Result<()> {
+    if !line.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "missing line break after chunk",
+        ));
+    }
+    Ok(())
+}
+
+// A line of `n` bytes read with at most MAX_CHUNK_LINE, without its line break
+pub(crate) fn chunk_line(line: String, n: usize) -> Result<String> {
+    if n == 0 {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "chunked body truncated",
+        ));
+    }
+    if !line.ends_with('\n') {
+        return Err(Error::new(ErrorKind::InvalidData, "chunk line too long"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::Stream;
+    use std::io::BufReader;
+    use std::net::{TcpListener, TcpStream};
+
+    // A request read from `raw`, the connection ends after it
+    fn request(raw: &str) -> Request {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        drop(client);
+        let reader = BufReader::new(Stream::new(raw.as_bytes().to_vec(), stream));
+        let mut request = Request::new(reader, address);
+        request.init().unwrap();
+        request
+    }
+
+    fn chunked(body: &str) -> Request {
+        request(&format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+            body
+        ))
+    }
+
+    fn read_body(request: &mut Request) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        request.body_reader().read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    #[test]
+    fn chunks_extensions_and_trailers() {
+        let mut request = chunked(
+            "5;name=value\r\nhello\r\n1A ; x\r\nabcdefghijklmnopqrstuvwxyz\r\n\
+             0\r\nX-Trailer: 1\r\nX-Other: 2\r\n\r\nGET /next HTTP/1.1\r\n",
+        );
+        assert_eq!(
+            read_body(&mut request).unwrap(),
+            b"helloabcdefghijklmnopqrstuvwxyz"
+        );
+
+        // The pipelined request behind the body is left alone
+        let mut next = String::new();
+        request.reader.read_line(&mut next).unwrap();
+        assert_eq!(next, "GET /next HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn bare_line_feeds() {
+        let mut request = chunked("3\nabc\n0\n\n");
+        assert_eq!(read_body(&mut request).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn truncated() {
+        for body in [
+            "",
+            "5\r\nhel",
+            "5\r\nhello",
+            "5\r\nhello\r\n",
+            "5\r\nhello\r\n0\r\n",
+        ] {
+            let err = read_body(&mut chunked(body)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{:?}", body);
+        }
+
+        let mut request = request("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello");
+        let err = read_body(&mut request).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed() {
+        for body in [
+            // No line break after the chunk data
+            "5\r\nhelloX\r\n0\r\n\r\n",
+            "zz\r\nhello\r\n0\r\n\r\n",
+            "-5\r\nhello\r\n0\r\n\r\n",
+            ";x\r\nhello\r\n0\r\n\r\n",
+        ] {
+            let err = read_body(&mut chunked(body)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", body);
+        }
+
+        let long = format!(
+            "5;{}\r\nhello\r\n0\r\n\r\n",
+            "x".repeat(MAX_CHUNK_LINE as usize)
+        );
+        let err = read_body(&mut chunked(&long)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn over_the_limit() {
+        let mut request = chunked("5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n");
+        request.body_limit = 8;
+        let err = read_body(&mut request).unwrap_err();
+        assert!(LimitError::of(&err).is_some());
+    }
+}
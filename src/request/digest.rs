@@ -0,0 +1,331 @@
+use super::Request;
+use crate::header::HeaderMap;
+use crate::hex;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{Error, ErrorKind, Result};
+
+/*
+Integrity of the request body.
+
+A client may send the digest of the body in one of
+
+    Content-MD5: Q2hlY2sgSW50ZWdyaXR5IQ==
+    Digest: sha-256=X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=
+    Repr-Digest: sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:
+    Content-Digest: sha-512=:...:
+
+md5, sha-256 and sha-512 are understood, other algorithms are ignored. The body is
+hashed while it's read, and when the end is reached a mismatch fails the read with
+`ErrorKind::InvalidData` (400 with `c.respond(err)`). So `save_body_to` never
+keeps a corrupted file and `parse_multipart_form` fails before returning any part.
+
+The digests computed are there for the handler once the body was read:
+
+    c.request.compute_body_digest("sha-256")?;
+    let saved = c.request.save_body_to("firmware.bin")?;
+    let sha256 = c.request.body_digest("sha-256").unwrap().hex();
+*/
+
+// A digest of the whole body
+#[derive(Debug, Clone)]
+pub struct BodyDigest {
+    // "md5", "sha-256" or "sha-512"
+    pub algorithm: &'static str,
+    pub value: Vec<u8>,
+}
+
+impl BodyDigest {
+    pub fn hex(&self) -> String {
+        hex::encode(&self.value)
+    }
+
+    pub fn base64(&self) -> String {
+        STANDARD.encode(&self.value)
+    }
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: &str) -> Option<(&'static str, Hasher)> {
+        match algorithm.to_ascii_lowercase().as_str() {
+            "md5" => Some(("md5", Hasher::Md5(Md5::new()))),
+            "sha-256" => Some(("sha-256", Hasher::Sha256(Sha256::new()))),
+            "sha-512" => Some(("sha-512", Hasher::Sha512(Sha512::new()))),
+            _ => None,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+// Hashing and checking of one request body
+#[derive(Default)]
+pub(crate) struct BodyDigests {
+    hashers: Vec<(&'static str, Hasher)>,
+    // Algorithm, digest sent and the header it came in
+    expected: Vec<(&'static str, Vec<u8>, &'static str)>,
+    computed: Vec<BodyDigest>,
+    // Set once the body didn't match, every further read fails
+    mismatch: Option<String>,
+}
+
+impl BodyDigests {
+    // The digests sent with the body, a malformed one is an error
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let mut digests = Self::default();
+        for value in headers.get_all("Content-MD5") {
+            digests.expect("md5", value.trim(), "Content-MD5")?;
+        }
+        for value in headers.get_all("Digest") {
+            for (algorithm, encoded) in pairs(value) {
+                digests.expect(algorithm, encoded, "Digest")?;
+            }
+        }
+        for name in ["Repr-Digest", "Content-Digest"] {
+            for value in headers.get_all(name) {
+                for (algorithm, encoded) in pairs(value) {
+                    if Hasher::new(algorithm).is_none() {
+                        continue;
+                    }
+                    // A byte sequence of structured fields, ":base64:"
+                    let encoded = encoded
+                        .strip_prefix(':')
+                        .and_then(|encoded| encoded.strip_suffix(':'))
+                        .ok_or_else(|| invalid(name))?;
+                    digests.expect(algorithm, encoded, name)?;
+                }
+            }
+        }
+        Ok(digests)
+    }
+
+    // Values of unknown algorithms aren't even decoded
+    fn expect(&mut self, algorithm: &str, encoded: &str, header: &'static str) -> Result<()> {
+        if let Some(algorithm) = self.hash(algorithm) {
+            let value = STANDARD.decode(encoded).map_err(|_| invalid(header))?;
+            self.expected.push((algorithm, value, header));
+        }
+        Ok(())
+    }
+
+    // Compute the digest with `algorithm`, None if it isn't supported
+    fn hash(&mut self, algorithm: &str) -> Option<&'static str> {
+        let (name, hasher) = Hasher::new(algorithm)?;
+        if !self.hashers.iter().any(|(known, _)| *known == name) {
+            self.hashers.push((name, hasher));
+        }
+        Some(name)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    // At the end of the body, fails if it doesn't match what was sent
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        for (algorithm, hasher) in std::mem::take(&mut self.hashers) {
+            self.computed.push(BodyDigest {
+                algorithm,
+                value: hasher.finalize(),
+            });
+        }
+        for (algorithm, value, header) in &self.expected {
+            let computed = self.computed.iter().find(|d| d.algorithm == *algorithm);
+            if computed.is_some_and(|computed| computed.value != *value) {
+                self.mismatch = Some(format!(
+                    "body doesn't match the {} digest in {}",
+                    algorithm, header
+                ));
+                break;
+            }
+        }
+        self.check()
+    }
+
+    // Err after a mismatch
+    pub(crate) fn check(&self) -> Result<()> {
+        match &self.mismatch {
+            Some(message) => Err(Error::new(ErrorKind::InvalidData, message.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Request {
+    // Also compute the digest with `algorithm` ("md5", "sha-256" or "sha-512") while
+    // the body is read. Has to be called before reading it.
+    pub fn compute_body_digest(&mut self, algorithm: &str) -> Result<()> {
+        match self.digests.hash(algorithm) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported digest algorithm: {}", algorithm),
+            )),
+        }
+    }
+
+    // Whether the client sent a digest the body is checked against
+    pub(crate) fn expects_body_digest(&self) -> bool {
+        !self.digests.expected.is_empty()
+    }
+
+    // The digest of the body with `algorithm`, once the whole body was read
+    pub fn body_digest(&self, algorithm: &str) -> Option<&BodyDigest> {
+        self.digests
+            .computed
+            .iter()
+            .find(|digest| digest.algorithm.eq_ignore_ascii_case(algorithm))
+    }
+}
+
+// "sha-256=abc, md5=def" -> [("sha-256", "abc"), ("md5", "def")]
+fn pairs(value: &str) -> impl Iterator<Item = (&str, &str)> {
+    value
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(algorithm, value)| (algorithm.trim(), value.trim()))
+}
+
+fn invalid(header: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid {} header", header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::from_raw;
+    use std::io::Read;
+
+    const MD5: &str = "XUFAKrxLKna5cZ2REBfFkg==";
+    const SHA256: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+
+    fn headers(fields: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            headers.append(name, value).unwrap();
+        }
+        headers
+    }
+
+    // "hello" sent with the given header fields
+    fn hello(fields: &str) -> Request {
+        from_raw(
+            format!(
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\n{}\r\nhello",
+                fields
+            )
+            .as_bytes(),
+        )
+    }
+
+    #[test]
+    fn parses_headers() {
+        let digests = BodyDigests::from_headers(&headers(&[
+            ("Content-MD5", MD5),
+            ("Digest", &format!("SHA-256={}, unixsum=30637", SHA256)),
+            (
+                "Repr-Digest",
+                &format!("sha-512=:{}:, md5=:{}:", SHA256, MD5),
+            ),
+        ]))
+        .unwrap();
+        let expected: Vec<(&str, &str)> = digests
+            .expected
+            .iter()
+            .map(|(algorithm, _, header)| (*algorithm, *header))
+            .collect();
+        assert_eq!(
+            expected,
+            [
+                ("md5", "Content-MD5"),
+                ("sha-256", "Digest"),
+                ("sha-512", "Repr-Digest"),
+                ("md5", "Repr-Digest"),
+            ]
+        );
+        assert_eq!(digests.expected[1].1, STANDARD.decode(SHA256).unwrap());
+        // One hasher per algorithm
+        assert_eq!(digests.hashers.len(), 3);
+    }
+
+    #[test]
+    fn ignores_unknown_algorithms() {
+        let digests = BodyDigests::from_headers(&headers(&[
+            ("Digest", "crc32c=not base64!"),
+            ("Content-Digest", "sha-1=no-colons"),
+        ]))
+        .unwrap();
+        assert!(digests.expected.is_empty() && digests.hashers.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        for fields in [
+            [("Content-MD5", "not base64!")],
+            [("Digest", "sha-256=%%%")],
+            [("Repr-Digest", &format!("sha-256={}", SHA256)[..])],
+            [("Content-Digest", &format!("md5=:{}", MD5)[..])],
+        ] {
+            let err = BodyDigests::from_headers(&headers(&fields)).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", fields);
+        }
+    }
+
+    #[test]
+    fn checks_the_body() {
+        let mut request = hello(&format!(
+            "Content-MD5: {}\r\nRepr-Digest: sha-256=:{}:\r\n",
+            MD5, SHA256
+        ));
+        assert!(request.expects_body_digest());
+        request.compute_body_digest("SHA-512").unwrap();
+        assert_eq!(request.body().unwrap(), b"hello");
+        assert_eq!(request.body_digest("md5").unwrap().base64(), MD5);
+        assert_eq!(
+            request.body_digest("sha-256").unwrap().hex(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(request.body_digest("sha-512").is_some());
+        assert!(request.compute_body_digest("crc32").is_err());
+    }
+
+    #[test]
+    fn mismatch_fails_the_read() {
+        for fields in [
+            format!("Content-MD5: {}\r\n", SHA256),
+            format!("Digest: sha-256={}, md5={}\r\n", MD5, MD5),
+            format!("Content-Digest: sha-256=:{}:\r\n", MD5),
+        ] {
+            let mut request = hello(&fields);
+            let err = request.body().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", fields);
+            // And keeps failing
+            let err = request.body_reader().read(&mut [0; 8]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
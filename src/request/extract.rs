@@ -0,0 +1,506 @@
+use crate::response::status::Status;
+use crate::response::{error_response, IntoResponse, Response};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/*
+Deserialize string key/value pairs (query, form, path params) into a struct.
+
+    #[derive(Deserialize)]
+    struct ApkQuery {
+        brand: String,
+        version: Option<u32>,
+        tag: Vec<String>,
+        debug: bool,
+    }
+
+Values are converted to the field's type, a key may repeat for `Vec` fields and a
+flag without value (`?debug`) is `true`. An empty value (`?version=`) leaves an
+`Option` field `None`. Instead of stopping at the first problem,
+every missing and invalid field is collected into `ExtractError`.
+*/
+pub fn extract<'a, T, I>(pairs: I) -> Result<T, ExtractError>
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let mut fields: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (key, value) in pairs {
+        match fields.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, values)) => values.push(value),
+            None => fields.push((key, vec![value])),
+        }
+    }
+
+    // serde reports a missing field by failing the whole struct. The field is then
+    // filled with a placeholder and deserialization retried, until every missing
+    // field is known.
+    let mut missing: Vec<String> = Vec::new();
+    loop {
+        let invalid = RefCell::new(Vec::new());
+        let deserializer = Fields {
+            fields: &fields,
+            placeholders: &missing,
+            invalid: &invalid,
+        };
+        let result = T::deserialize(deserializer);
+        let mut invalid = invalid.into_inner();
+
+        match result {
+            Ok(value) if missing.is_empty() && invalid.is_empty() => return Ok(value),
+            Ok(_) => return Err(ExtractError { missing, invalid }),
+            Err(Error::Missing(field)) if !missing.contains(&field) => missing.push(field),
+            Err(err) => {
+                invalid.push(("".to_string(), err.to_string()));
+                return Err(ExtractError { missing, invalid });
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExtractError {
+    pub missing: Vec<String>,
+    // (field, reason)
+    pub invalid: Vec<(String, String)>,
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut problems = Vec::new();
+        if !self.missing.is_empty() {
+            problems.push(format!("missing fields: {}", self.missing.join(", ")));
+        }
+        if !self.invalid.is_empty() {
+            let invalid: Vec<String> = self
+                .invalid
+                .iter()
+                .map(|(field, reason)| format!("{} ({})", field, reason))
+                .collect();
+            problems.push(format!("invalid fields: {}", invalid.join(", ")));
+        }
+        write!(f, "{}", problems.join("; "))
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+// 400 with the offending fields, e.g.
+// {"code":400,"message":"missing fields: brand","missing":["brand"],"invalid":[]}
+impl IntoResponse for ExtractError {
+    fn into_response(self) -> Response {
+        let invalid: Vec<HashMap<&str, &str>> = self
+            .invalid
+            .iter()
+            .map(|(field, reason)| HashMap::from([("field", &field[..]), ("reason", &reason[..])]))
+            .collect();
+        let extra = serde_json::json!({
+            "missing": self.missing,
+            "invalid": invalid,
+        });
+        error_response(Status::BadRequest, &self.to_string(), extra)
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    Missing(String),
+    Custom(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Missing(field) => write!(f, "missing field `{}`", field),
+            Error::Custom(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        Error::Missing(field.to_string())
+    }
+}
+
+type Invalid = RefCell<Vec<(String, String)>>;
+
+struct Fields<'a> {
+    fields: &'a [(&'a str, Vec<&'a str>)],
+    placeholders: &'a [String],
+    invalid: &'a Invalid,
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Fields<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(FieldsAccess {
+            fields: self.fields,
+            placeholders: self.placeholders,
+            invalid: self.invalid,
+            index: 0,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct FieldsAccess<'a> {
+    fields: &'a [(&'a str, Vec<&'a str>)],
+    placeholders: &'a [String],
+    invalid: &'a Invalid,
+    index: usize,
+}
+
+impl<'a> FieldsAccess<'a> {
+    // Present fields first, then the placeholders for missing ones
+    fn current(&self) -> Option<(&'a str, &'a [&'a str])> {
+        let i = self.index;
+        if i < self.fields.len() {
+            let (key, values) = &self.fields[i];
+            Some((key, values))
+        } else {
+            self.placeholders
+                .get(i - self.fields.len())
+                .map(|key| (key.as_str(), &[][..]))
+        }
+    }
+}
+
+impl<'de, 'a> de::MapAccess<'de> for FieldsAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.current() {
+            Some((key, _)) => seed.deserialize(key.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (field, values) = self.current().unwrap();
+        self.index += 1;
+        seed.deserialize(Value {
+            field,
+            values,
+            invalid: self.invalid,
+        })
+    }
+}
+
+// The values of one field. An empty list is a placeholder for a missing field and
+// deserializes to a default without reporting anything.
+struct Value<'a> {
+    field: &'a str,
+    values: &'a [&'a str],
+    invalid: &'a Invalid,
+}
+
+impl<'a> Value<'a> {
+    fn first(&self) -> Option<&'a str> {
+        self.values.first().map(|value| value.trim())
+    }
+
+    fn invalid(&self, reason: String) {
+        self.invalid
+            .borrow_mut()
+            .push((self.field.to_string(), reason));
+    }
+}
+
+macro_rules! deserialize_parse {
+    ($($method:ident => $visit:ident($ty:ty),)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                let value = match self.first() {
+                    None => <$ty>::default(),
+                    Some(raw) => raw.parse::<$ty>().unwrap_or_else(|_| {
+                        self.invalid(format!("expected {}, got {:?}", stringify!($ty), raw));
+                        <$ty>::default()
+                    }),
+                };
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Value<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.first().unwrap_or(""))
+    }
+
+    deserialize_parse! {
+        deserialize_i8 => visit_i8(i8),
+        deserialize_i16 => visit_i16(i16),
+        deserialize_i32 => visit_i32(i32),
+        deserialize_i64 => visit_i64(i64),
+        deserialize_i128 => visit_i128(i128),
+        deserialize_u8 => visit_u8(u8),
+        deserialize_u16 => visit_u16(u16),
+        deserialize_u32 => visit_u32(u32),
+        deserialize_u64 => visit_u64(u64),
+        deserialize_u128 => visit_u128(u128),
+        deserialize_f32 => visit_f32(f32),
+        deserialize_f64 => visit_f64(f64),
+        deserialize_char => visit_char(char),
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let value = match self.first() {
+            None => false,
+            // A flag like `?debug` or a checked checkbox
+            Some("" | "true" | "1" | "on" | "yes") => true,
+            Some("false" | "0" | "off" | "no") => false,
+            Some(raw) => {
+                self.invalid(format!("expected bool, got {:?}", raw));
+                false
+            }
+        };
+        visitor.visit_bool(value)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.first().unwrap_or("").is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(ValuesAccess {
+            field: self.field,
+            values: self.values,
+            invalid: self.invalid,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Only unit variants, selected by name
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let variant = match self.first() {
+            Some(raw) if variants.contains(&raw) => raw,
+            Some(raw) => {
+                self.invalid(format!("expected one of {:?}, got {:?}", variants, raw));
+                variants.first().copied().unwrap_or("")
+            }
+            None => variants.first().copied().unwrap_or(""),
+        };
+        visitor.visit_enum(variant.into_deserializer())
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf map struct identifier
+    }
+}
+
+struct ValuesAccess<'a> {
+    field: &'a str,
+    values: &'a [&'a str],
+    invalid: &'a Invalid,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for ValuesAccess<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.values.split_first() {
+            None => Ok(None),
+            Some((first, rest)) => {
+                let value = Value {
+                    field: self.field,
+                    values: std::slice::from_ref(first),
+                    invalid: self.invalid,
+                };
+                self.values = rest;
+                seed.deserialize(value).map(Some)
+            }
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.values.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Channel {
+        Stable,
+        Beta,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct ApkQuery {
+        brand: String,
+        version: Option<u32>,
+        #[serde(default)]
+        tag: Vec<String>,
+        #[serde(default)]
+        debug: bool,
+        channel: Option<Channel>,
+    }
+
+    fn query(pairs: &[(&'static str, &'static str)]) -> Result<ApkQuery, ExtractError> {
+        extract(pairs.iter().copied())
+    }
+
+    #[test]
+    fn converts_fields() {
+        let q = query(&[
+            ("brand", "acme"),
+            ("version", " 42 "),
+            ("tag", "a"),
+            ("tag", "b"),
+            ("debug", "on"),
+            ("channel", "beta"),
+            ("unknown", "ignored"),
+        ])
+        .unwrap();
+        assert_eq!(q.brand, "acme");
+        assert_eq!(q.version, Some(42));
+        assert_eq!(q.tag, ["a", "b"]);
+        assert!(q.debug);
+        assert_eq!(q.channel, Some(Channel::Beta));
+    }
+
+    #[test]
+    fn optional_fields() {
+        let q = query(&[("brand", "acme")]).unwrap();
+        assert_eq!(q.version, None);
+        assert!(q.tag.is_empty());
+        assert!(!q.debug);
+        assert_eq!(q.channel, None);
+
+        let q = query(&[("brand", "acme"), ("version", ""), ("channel", "")]).unwrap();
+        assert_eq!(q.version, None);
+        assert_eq!(q.channel, None);
+    }
+
+    #[test]
+    fn bools() {
+        for (raw, expected) in [
+            ("", true),
+            ("true", true),
+            ("1", true),
+            ("yes", true),
+            ("false", false),
+            ("0", false),
+            ("off", false),
+            ("no", false),
+        ] {
+            let q = query(&[("brand", "acme"), ("debug", raw)]).unwrap();
+            assert_eq!(q.debug, expected, "{:?}", raw);
+        }
+
+        let err = query(&[("brand", "acme"), ("debug", "maybe")]).unwrap_err();
+        assert_eq!(err.invalid[0].0, "debug");
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let err = query(&[("version", "x"), ("channel", "nightly"), ("debug", "2")]).unwrap_err();
+        assert_eq!(err.missing, ["brand"]);
+        let fields: Vec<&str> = err.invalid.iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(fields, ["version", "channel", "debug"]);
+        assert!(err.invalid[0].1.contains("u32"));
+        assert!(err.invalid[1].1.contains("nightly"));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "missing fields: brand; invalid fields: {}",
+                err.invalid
+                    .iter()
+                    .map(|(f, r)| format!("{} ({})", f, r))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        );
+    }
+
+    #[test]
+    fn missing_only() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Login {
+            user: String,
+            password: String,
+        }
+        let err = extract::<Login, _>([("other", "1")]).unwrap_err();
+        assert_eq!(err.missing, ["user", "password"]);
+        assert!(err.invalid.is_empty());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::response::status::Status;
+use crate::response::{error_response, IntoResponse, Response};
+use std::fmt;
+
+// Why a JSON request body could not be turned into the expected type
+#[derive(Debug)]
+pub enum JsonError {
+    // 415, the Content-Type is not JSON
+    UnsupportedMediaType(String),
+    // 413, Content-Length is above the limit, or a chunked body went over it
+    ContentTooLarge {
+        length: usize,
+        limit: usize,
+    },
+    // 400, the body couldn't be read, e.g. it's truncated or doesn't match its digest
+    Body(String),
+    // 400, malformed JSON or a value that doesn't fit the type
+    Invalid {
+        message: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+impl JsonError {
+    pub fn status(&self) -> Status {
+        match self {
+            JsonError::UnsupportedMediaType(_) => Status::UnsupportedMediaType,
+            JsonError::ContentTooLarge { .. } => Status::ContentTooLarge,
+            JsonError::Body(_) | JsonError::Invalid { .. } => Status::BadRequest,
+        }
+    }
+}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(err: serde_json::Error) -> Self {
+        JsonError::Invalid {
+            message: err.to_string(),
+            line: err.line(),
+            column: err.column(),
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::UnsupportedMediaType(content_type) => {
+                write!(f, "expected application/json, got {:?}", content_type)
+            }
+            JsonError::ContentTooLarge { length, limit } => {
+                write!(f, "body of {} bytes exceeds the limit of {}", length, limit)
+            }
+            JsonError::Body(message) | JsonError::Invalid { message, .. } => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+// e.g. {"code":400,"message":"expected `,` or `}` at line 1 column 9","line":1,"column":9}
+impl IntoResponse for JsonError {
+    fn into_response(self) -> Response {
+        let extra = match self {
+            JsonError::Invalid { line, column, .. } => {
+                serde_json::json!({ "line": line, "column": column })
+            }
+            _ => serde_json::Value::Null,
+        };
+        error_response(self.status(), &self.to_string(), extra)
+    }
+}
@@ -0,0 +1,333 @@
+use crate::response::status::Status;
+use crate::response::{error_response, IntoResponse, Response};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+
+/*
+Limits for multipart bodies, for the whole server (`RustWeb::multipart_limits`) or
+one route (`RustWeb::route_multipart_limits`).
+
+    let limits = MultipartLimits::new()
+        .max_parts(10)
+        .max_file_size(20 << 20)
+        .allowed_types(&["image/png", "image/jpeg"])
+        .allowed_extensions(&["png", "jpg", "jpeg"]);
+    web.route_multipart_limits("/avatar", limits);
+
+A violation ends the multipart body with an `io::Error` carrying a `LimitError`,
+`c.respond(err)` answers it with 413 or 415.
+*/
+#[derive(Debug, Clone)]
+pub struct MultipartLimits {
+    pub max_parts: usize,
+    // Header block of one part
+    pub max_header_size: usize,
+    // Body of a part without filename
+    pub max_field_size: u64,
+    pub max_file_size: u64,
+    // Content types of file parts like "image/png" or "image/*", any if empty
+    pub allowed_types: Vec<String>,
+    // Filename extensions of file parts without the dot, any if empty
+    pub allowed_extensions: Vec<String>,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_parts: 1000,
+            max_header_size: 16 << 10,
+            max_field_size: 10 << 20,
+            // Only the body limit
+            max_file_size: u64::MAX,
+            allowed_types: vec![],
+            allowed_extensions: vec![],
+        }
+    }
+}
+
+impl MultipartLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_parts(mut self, max_parts: usize) -> Self {
+        self.max_parts = max_parts;
+        self
+    }
+
+    pub fn max_header_size(mut self, max_header_size: usize) -> Self {
+        self.max_header_size = max_header_size;
+        self
+    }
+
+    pub fn max_field_size(mut self, max_field_size: u64) -> Self {
+        self.max_field_size = max_field_size;
+        self
+    }
+
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn allowed_types(mut self, types: &[&str]) -> Self {
+        self.allowed_types = types.iter().map(|t| t.to_ascii_lowercase()).collect();
+        self
+    }
+
+    pub fn allowed_extensions(mut self, extensions: &[&str]) -> Self {
+        self.allowed_extensions = extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    // Whether a file part with this Content-Type and filename may be uploaded
+    pub(crate) fn check_file(
+        &self,
+        name: &str,
+        content_type: &str,
+        filename: &str,
+    ) -> Result<(), LimitError> {
+        // Without parameters, "image/png; x=y" is "image/png"
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        if !self.allowed_types.is_empty()
+            && !self
+                .allowed_types
+                .iter()
+                .any(|allowed| type_matches(allowed, &content_type))
+        {
+            return Err(LimitError::UnsupportedType {
+                name: name.to_string(),
+                content_type,
+            });
+        }
+
+        let extension = match filename.rsplit_once('.') {
+            Some((_, extension)) => extension.to_ascii_lowercase(),
+            None => "".to_string(),
+        };
+        if !self.allowed_extensions.is_empty() && !self.allowed_extensions.contains(&extension) {
+            return Err(LimitError::UnsupportedExtension {
+                name: name.to_string(),
+                filename: filename.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+// "image/*" matches any image
+fn type_matches(allowed: &str, content_type: &str) -> bool {
+    match allowed.strip_suffix("/*") {
+        Some(main_type) => content_type
+            .split_once('/')
+            .is_some_and(|(main, _)| main == main_type),
+        None => allowed == content_type,
+    }
+}
+
+static MULTIPART_LIMITS: OnceLock<MultipartLimits> = OnceLock::new();
+
+// Only once, a second call panics
+pub fn set_multipart_limits(limits: MultipartLimits) {
+    if MULTIPART_LIMITS.set(limits).is_err() {
+        panic!("multipart limits are already set");
+    }
+}
+
+// Limits of routes without their own
+pub(crate) fn multipart_limits() -> MultipartLimits {
+    MULTIPART_LIMITS.get().cloned().unwrap_or_default()
+}
+
+// A request body over one of the limits
+#[derive(Debug, Clone)]
+pub enum LimitError {
+    // 413
+    BodyTooLarge { limit: usize },
+    TooManyParts { limit: usize },
+    HeaderTooLarge { limit: usize },
+    FieldTooLarge { name: String, limit: u64 },
+    FileTooLarge { name: String, limit: u64 },
+    // 415
+    UnsupportedType { name: String, content_type: String },
+    UnsupportedExtension { name: String, filename: String },
+}
+
+impl LimitError {
+    pub fn status(&self) -> Status {
+        match self {
+            LimitError::UnsupportedType { .. } | LimitError::UnsupportedExtension { .. } => {
+                Status::UnsupportedMediaType
+            }
+            _ => Status::ContentTooLarge,
+        }
+    }
+
+    // The limit error inside an I/O error, if that's what it is
+    pub fn of(err: &Error) -> Option<&LimitError> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<LimitError>())
+    }
+}
+
+impl From<LimitError> for Error {
+    fn from(err: LimitError) -> Self {
+        Error::new(ErrorKind::InvalidData, err)
+    }
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::BodyTooLarge { limit } => {
+                write!(f, "body exceeds the limit of {} bytes", limit)
+            }
+            LimitError::TooManyParts { limit } => {
+                write!(f, "more than {} multipart parts", limit)
+            }
+            LimitError::HeaderTooLarge { limit } => {
+                write!(f, "part header exceeds the limit of {} bytes", limit)
+            }
+            LimitError::FieldTooLarge { name, limit } => {
+                write!(f, "field {:?} exceeds the limit of {} bytes", name, limit)
+            }
+            LimitError::FileTooLarge { name, limit } => {
+                write!(f, "file {:?} exceeds the limit of {} bytes", name, limit)
+            }
+            LimitError::UnsupportedType { name, content_type } => {
+                write!(f, "file {:?} has unsupported type {:?}", name, content_type)
+            }
+            LimitError::UnsupportedExtension { name, filename } => {
+                write!(f, "file {:?} has unsupported name {:?}", name, filename)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+// e.g. {"code":415,"message":"file \"avatar\" has unsupported type \"image/gif\""}
+impl IntoResponse for LimitError {
+    fn into_response(self) -> Response {
+        error_response(self.status(), &self.to_string(), serde_json::Value::Null)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::from_raw;
+    use std::io::Read;
+
+    #[test]
+    fn type_matching() {
+        assert!(type_matches("image/*", "image/png"));
+        assert!(type_matches("image/png", "image/png"));
+        assert!(!type_matches("image/*", "imagex/png"));
+        assert!(!type_matches("image/*", "image"));
+        assert!(!type_matches("image/png", "image/pngx"));
+        assert!(!type_matches("text/*", "application/text"));
+    }
+
+    #[test]
+    fn checks_files() {
+        let limits = MultipartLimits::new()
+            .allowed_types(&["IMAGE/*", "application/pdf"])
+            .allowed_extensions(&[".PNG", "pdf"]);
+        assert!(limits.check_file("f", "image/png; x=y", "a.png").is_ok());
+        assert!(limits.check_file("f", "Image/PNG", "dir.v2/A.PnG").is_ok());
+        assert!(limits.check_file("f", "application/pdf", "a.pdf").is_ok());
+
+        match limits.check_file("f", "text/plain; charset=utf-8", "a.png") {
+            Err(LimitError::UnsupportedType { content_type, .. }) => {
+                assert_eq!(content_type, "text/plain")
+            }
+            other => panic!("{:?}", other),
+        }
+        for filename in ["a.gif", "png", "a.png.exe", ""] {
+            let err = limits.check_file("f", "image/png", filename).unwrap_err();
+            assert!(matches!(err, LimitError::UnsupportedExtension { .. }));
+            assert_eq!(err.status(), Status::UnsupportedMediaType);
+        }
+        // Anything goes by default
+        assert!(MultipartLimits::new().check_file("f", "", "").is_ok());
+    }
+
+    // The errors of reading every part of a body with these parts
+    fn read_parts(limits: MultipartLimits, parts: &[(&str, &str)]) -> Vec<LimitError> {
+        let mut body = String::new();
+        for (disposition, content) in parts {
+            body += &format!(
+                "--b\r\nContent-Disposition: form-data; {}\r\n\r\n{}\r\n",
+                disposition, content
+            );
+        }
+        body += "--b--\r\n";
+        let mut request = from_raw(
+            format!(
+                "POST / HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\n\
+                 Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        );
+        request.multipart_limits = limits;
+
+        let mut errors = Vec::new();
+        for part in request.multipart() {
+            let result = part.and_then(|mut part| part.read_to_end(&mut Vec::new()));
+            if let Err(err) = result {
+                errors.push(LimitError::of(&err).unwrap().clone());
+            }
+        }
+        errors
+    }
+
+    #[test]
+    fn enforced_while_reading() {
+        let parts = [
+            ("name=a", "12345"),
+            ("name=b; filename=b.txt", "1234567890"),
+            ("name=c", "123456"),
+        ];
+        let errors = read_parts(MultipartLimits::new().max_parts(2), &parts);
+        assert!(matches!(
+            errors[..],
+            [LimitError::TooManyParts { limit: 2 }]
+        ));
+
+        let errors = read_parts(MultipartLimits::new().max_field_size(5), &parts);
+        match &errors[..] {
+            [LimitError::FieldTooLarge { name, limit: 5 }] => assert_eq!(name, "c"),
+            other => panic!("{:?}", other),
+        }
+
+        let errors = read_parts(MultipartLimits::new().max_file_size(9), &parts);
+        match &errors[..] {
+            [LimitError::FileTooLarge { name, limit: 9 }] => assert_eq!(name, "b"),
+            other => panic!("{:?}", other),
+        }
+
+        // Files without Content-Type are application/octet-stream
+        let limits = MultipartLimits::new().allowed_types(&["text/*"]);
+        let errors = read_parts(limits, &parts);
+        match &errors[..] {
+            [LimitError::UnsupportedType { content_type, .. }] => {
+                assert_eq!(content_type, "application/octet-stream")
+            }
+            other => panic!("{:?}", other),
+        }
+
+        assert!(read_parts(MultipartLimits::new(), &parts).is_empty());
+    }
+}
@@ -1 +1,2 @@
+pub mod formdata;
 pub mod multipart;
\ No newline at end of file
@@ -0,0 +1,286 @@
+use crate::content_type::ContentType;
+use crate::header::HeaderMap;
+use crate::request::upload::{self, TempFile};
+use crate::request::Request;
+use std::fs;
+use std::io::{prelude::*, Error, ErrorKind, Result};
+use std::path::Path;
+
+/*
+A whole multipart/form-data body at once.
+
+    c.request.parse_multipart_form(32 << 20)?;
+    let nickname = c.request.post_form.get("nickname");
+    if let Some(avatar) = c.request.form_file("avatar") {
+        avatar.save_to(format!("avatars/{}", avatar.filename))?;
+    }
+
+Text fields go to `form` and `post_form`. Files are kept in memory as long as they
+fit into `max_memory` together, larger ones are spilled to temp files in the
+upload directory. The temp files are removed with the request.
+*/
+
+// Used by `form_as` for multipart bodies
+pub const DEFAULT_MAX_MEMORY: usize = 32 << 20; // 32MB
+
+// Text fields may use this much on top of `max_memory`
+const MAX_VALUES_EXTRA: usize = 10 << 20; // 10MB
+
+pub struct FormFile {
+    // Without directories, empty if the client sent none
+    pub filename: String,
+    pub header: HeaderMap,
+    pub content_type: ContentType,
+    pub size: u64,
+    content: Content,
+}
+
+enum Content {
+    Memory(Vec<u8>),
+    Disk(TempFile),
+}
+
+impl FormFile {
+    pub fn open(&self) -> Result<Box<dyn Read + '_>> {
+        match &self.content {
+            Content::Memory(data) => Ok(Box::new(&data[..])),
+            Content::Disk(temp) => Ok(Box::new(temp.open()?)),
+        }
+    }
+
+    // Where the file was spilled to, None while it's in memory
+    pub fn path(&self) -> Option<&Path> {
+        match &self.content {
+            Content::Memory(_) => None,
+            Content::Disk(temp) => Some(temp.path()),
+        }
+    }
+
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        match &self.content {
+            Content::Memory(data) => fs::write(path, data),
+            Content::Disk(temp) => fs::copy(temp.path(), path).map(|_| ()),
+        }
+    }
+}
+
+impl Request {
+    // Read the whole multipart body, see above. Calling it again does nothing.
+    pub fn parse_multipart_form(&mut self, max_memory: usize) -> Result<()> {
+        if self.multipart_parsed {
+            return Ok(());
+        }
+        self.multipart_parsed = true;
+
+        let mut values: Vec<(String, String)> = Vec::new();
+        let mut files: Vec<(String, FormFile)> = Vec::new();
+        let mut file_memory = max_memory;
+        let mut value_memory = max_memory + MAX_VALUES_EXTRA;
+
+        for part in self.multipart() {
+            let mut part = part?;
+            let name = match part.name() {
+                Some(name) => name.to_string(),
+                // Not a form field, skipped
+                None => continue,
+            };
+
+            if part.disposition.filename.is_none() {
+                let mut value = Vec::new();
+                (&mut part)
+                    .take(value_memory as u64 + 1)
+                    .read_to_end(&mut value)?;
+                if value.len() > value_memory {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "multipart form values too large",
+                    ));
+                }
+                value_memory -= value.len();
+                let value = String::from_utf8(value).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("form value is not UTF-8: {}", name),
+                    )
+                })?;
+                values.push((name, value));
+                continue;
+            }
+
+            // Read what fits into memory, one byte more tells whether it's all
+            let mut data = Vec::new();
+            (&mut part)
+                .take(file_memory as u64 + 1)
+                .read_to_end(&mut data)?;
+            let content = if data.len() > file_memory {
+                Content::Disk(upload::spool(&mut part, &upload::upload_dir()?, &data)?)
+            } else {
+                file_memory -= data.len();
+                Content::Memory(data)
+            };
+
+            let size = match &content {
+                Content::Memory(data) => data.len() as u64,
+                Content::Disk(temp) => temp.size(),
+            };
+            let file = FormFile {
+                filename: part.filename().unwrap_or("").to_string(),
+                header: std::mem::take(&mut part.header),
+                content_type: std::mem::replace(&mut part.content_type, ContentType::None),
+                size,
+                content,
+            };
+            files.push((name, file));
+        }
+
+        for (name, value) in values {
+            self.form
+                .entry(name.clone())
+                .or_default()
+                .push(value.clone());
+            self.post_form.entry(name).or_default().push(value);
+        }
+        for (name, file) in files {
+            self.files.entry(name).or_default().push(file);
+        }
+        Ok(())
+    }
+
+    // The first file sent for the field
+    pub fn form_file(&self, name: &str) -> Option<&FormFile> {
+        self.files.get(name).and_then(|files| files.first())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request::{from_raw, Request};
+    use std::io::{ErrorKind, Read};
+
+    // A multipart/form-data request, parts are (header lines, content)
+    fn form(parts: &[(&str, &[u8])]) -> Request {
+        let mut body = Vec::new();
+        for (header, content) in parts {
+            body.extend_from_slice(format!("--xyz\r\n{}\r\n\r\n", header).as_bytes());
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--xyz--\r\n");
+        let head = format!(
+            "POST / HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\n\
+             Content-Length: {}\r\n\r\n",
+            body.len()
+        );
+        from_raw(&[head.into_bytes(), body].concat())
+    }
+
+    fn read(file: &super::FormFile) -> Vec<u8> {
+        let mut content = Vec::new();
+        file.open().unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn collects_values_and_files() {
+        let mut request = form(&[
+            (
+                "Content-Disposition: form-data; name=\"nickname\"",
+                b"sfdex",
+            ),
+            ("Content-Disposition: form-data; name=\"tag\"", b"a"),
+            ("Content-Disposition: attachment", b"no name, skipped"),
+            (
+                "Content-Disposition: form-data; name=\"avatar\"; filename=\"C:\\me\\a.png\"\r\n\
+                 Content-Type: image/png",
+                b"\x89PNG\r\n",
+            ),
+            (
+                "Content-Disposition: form-data; name=\"tag\"",
+                "ü".as_bytes(),
+            ),
+            (
+                "Content-Disposition: form-data; name=\"doc\"; filename=\"\"",
+                b"",
+            ),
+            (
+                "Content-Disposition: form-data; name=\"doc\"; filename=\"b.txt\"",
+                b"second",
+            ),
+        ]);
+        request.parse_multipart_form(1 << 20).unwrap();
+
+        assert_eq!(request.form["nickname"], ["sfdex"]);
+        assert_eq!(request.post_form["tag"], ["a", "ü"]);
+        assert_eq!(request.form.len(), 2);
+
+        let avatar = request.form_file("avatar").unwrap();
+        assert_eq!(avatar.filename, "a.png");
+        assert_eq!(avatar.size, 6);
+        assert_eq!(avatar.header.get("content-type"), Some("image/png"));
+        assert!(avatar.path().is_none());
+        assert_eq!(read(avatar), b"\x89PNG\r\n");
+
+        let docs = &request.files["doc"];
+        assert_eq!(docs.len(), 2);
+        assert_eq!((docs[0].filename.as_str(), docs[0].size), ("", 0));
+        assert_eq!(read(&docs[1]), b"second");
+        assert!(request.form_file("nickname").is_none());
+
+        // Already parsed
+        request.parse_multipart_form(1 << 20).unwrap();
+        assert_eq!(request.post_form["tag"].len(), 2);
+    }
+
+    #[test]
+    fn spills_large_files() {
+        let big = vec![7u8; 3000];
+        let mut request = form(&[
+            (
+                "Content-Disposition: form-data; name=\"small\"; filename=\"s\"",
+                &[1; 600],
+            ),
+            (
+                "Content-Disposition: form-data; name=\"big\"; filename=\"b\"",
+                &big,
+            ),
+            // Doesn't fit next to the first one anymore
+            (
+                "Content-Disposition: form-data; name=\"next\"; filename=\"n\"",
+                &[2; 600],
+            ),
+        ]);
+        request.parse_multipart_form(1000).unwrap();
+
+        assert!(request.form_file("small").unwrap().path().is_none());
+        let next = request.form_file("next").unwrap();
+        assert!(next.path().is_some());
+        assert_eq!(read(next), [2; 600]);
+
+        let big = request.form_file("big").unwrap();
+        let path = big.path().unwrap().to_path_buf();
+        assert_eq!(big.size, 3000);
+        assert_eq!(std::fs::read(&path).unwrap(), vec![7u8; 3000]);
+        let copy = std::env::temp_dir().join(format!("rustweb-big-{}", std::process::id()));
+        big.save_to(&copy).unwrap();
+        assert_eq!(std::fs::read(&copy).unwrap().len(), 3000);
+        std::fs::remove_file(copy).unwrap();
+
+        // Temp files go with the request
+        drop(request);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn invalid_values() {
+        let mut request = form(&[("Content-Disposition: form-data; name=\"v\"", b"\xff")]);
+        let err = request.parse_multipart_form(1 << 20).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let long = vec![b'a'; 200];
+        let mut request = form(&[("Content-Disposition: form-data; name=\"v\"", &long)]);
+        request.multipart_limits.max_field_size = 100;
+        let err = request.parse_multipart_form(1 << 20).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(request.form.is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::request::percent_decode_bytes;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+
+/*
+The Content-Disposition of a part.
+
+    form-data; name="myFile"; filename="foo.txt"
+    form-data; name="doc"; filename="a \"b\".txt"; filename*=UTF-8''%E2%82%AC%20rates.txt
+
+Quoted values are unescaped. A `name*` or `filename*` parameter (RFC 5987) is
+decoded and takes precedence over the plain one, which clients keep for older
+servers.
+*/
+#[derive(Debug, Default, Clone)]
+pub struct ContentDisposition {
+    // "form-data", "attachment", ... in lowercase
+    pub disposition_type: String,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    // All parameters by lowercase name, `filename*` is stored as `filename`
+    pub params: HashMap<String, String>,
+}
+
+impl ContentDisposition {
+    pub fn parse(value: &str) -> Result<Self> {
+        let (disposition_type, mut rest) = match value.split_once(';') {
+            Some((disposition_type, rest)) => (disposition_type, rest),
+            None => (value, ""),
+        };
+
+        let mut params = HashMap::new();
+        // Parameters given as `name*=`, they win over the plain ones
+        let mut extended = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ';' || c.is_ascii_whitespace());
+            if rest.is_empty() {
+                break;
+            }
+
+            let (name, after) = rest
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("parameter without value: {:?}", rest.trim())))?;
+            let name = name.trim().to_ascii_lowercase();
+            let after = after.trim_start();
+            let (value, after) = if after.starts_with('"') {
+                quoted_string(after)?
+            } else {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            };
+            rest = after;
+
+            match name.strip_suffix('*') {
+                Some(name) => {
+                    let value = ext_value(&value)?;
+                    params.insert(name.to_string(), value);
+                    extended.push(name.to_string());
+                }
+                None if extended.contains(&name) => (),
+                None => {
+                    params.entry(name).or_insert(value);
+                }
+            }
+        }
+
+        Ok(Self {
+            disposition_type: disposition_type.trim().to_ascii_lowercase(),
+            name: params.get("name").cloned(),
+            filename: params.get("filename").cloned(),
+            params,
+        })
+    }
+}
+
+// "a \"b\".txt"; ... -> (a "b".txt, ; ...)
+// A backslash only escapes `"` and `\`, others are kept so that a Windows path
+// like "C:\dir\a.txt" from old browsers survives.
+fn quoted_string(input: &str) -> Result<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &input[i + 1..])),
+            '\\' => match chars.next_if(|(_, next)| *next == '"' || *next == '\\') {
+                Some((_, escaped)) => value.push(escaped),
+                None => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    Err(invalid(format!("unterminated quoted string: {}", input)))
+}
+
+// charset'language'percent-encoded, e.g. UTF-8''%E2%82%AC%20rates.txt
+fn ext_value(value: &str) -> Result<String> {
+    let mut fields = value.splitn(3, '\'');
+    let (charset, encoded) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(charset), Some(_language), Some(encoded)) => (charset, encoded),
+        _ => return Err(invalid(format!("invalid extended value: {}", value))),
+    };
+
+    let bytes = percent_decode_bytes(encoded, false)?;
+    if charset.eq_ignore_ascii_case("UTF-8") {
+        String::from_utf8(bytes).map_err(|_| invalid(format!("invalid UTF-8: {}", value)))
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        // The first 256 code points are Latin-1
+        Ok(bytes.into_iter().map(char::from).collect())
+    } else {
+        Err(invalid(format!("unsupported charset: {}", charset)))
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> ContentDisposition {
+        ContentDisposition::parse(value).unwrap()
+    }
+
+    #[test]
+    fn plain_and_quoted() {
+        let d = parse(r#"Form-Data; NAME="myFile"; filename=foo.txt ; size=3"#);
+        assert_eq!(d.disposition_type, "form-data");
+        assert_eq!(d.name.as_deref(), Some("myFile"));
+        assert_eq!(d.filename.as_deref(), Some("foo.txt"));
+        assert_eq!(d.params["size"], "3");
+
+        let d = parse("attachment");
+        assert_eq!(d.disposition_type, "attachment");
+        assert!(d.name.is_none() && d.params.is_empty());
+
+        // A semicolon inside quotes is part of the value
+        let d = parse(r#"form-data; name="a;b"; filename="""#);
+        assert_eq!(d.name.as_deref(), Some("a;b"));
+        assert_eq!(d.filename.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn quoted_string_escapes() {
+        let d = parse(r#"form-data; name="doc"; filename="a \"b\" \\ c.txt""#);
+        assert_eq!(d.filename.as_deref(), Some(r#"a "b" \ c.txt"#));
+
+        // Other backslashes are kept
+        let d = parse(r#"form-data; name="f"; filename="C:\dir\a.txt""#);
+        assert_eq!(d.filename.as_deref(), Some(r"C:\dir\a.txt"));
+    }
+
+    #[test]
+    fn extended_values() {
+        let d = parse("form-data; name=f; filename*=UTF-8''%E2%82%AC%20rates.txt");
+        assert_eq!(d.filename.as_deref(), Some("€ rates.txt"));
+
+        let d = parse("attachment; filename*=iso-8859-1'en'caf%E9.txt");
+        assert_eq!(d.filename.as_deref(), Some("café.txt"));
+
+        // The extended value wins, wherever it is
+        for value in [
+            r#"form-data; filename="fallback.txt"; filename*=UTF-8''%C3%A9.txt"#,
+            r#"form-data; filename*=UTF-8''%C3%A9.txt; filename="fallback.txt""#,
+        ] {
+            assert_eq!(parse(value).filename.as_deref(), Some("é.txt"), "{}", value);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for value in [
+            "form-data; name",
+            r#"form-data; name="unterminated"#,
+            r#"form-data; name="a\""#,
+            "form-data; filename*=no-quotes.txt",
+            "form-data; filename*=UTF-8''%ZZ",
+            "form-data; filename*=UTF-8''%FF",
+            "form-data; filename*=KOI8-R''abc",
+        ] {
+            let err = ContentDisposition::parse(value).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use crate::header::HeaderMap;
+use crate::request::limits::LimitError;
+use memchr::memmem::Finder;
+use std::io::{self, prelude::*, Error, ErrorKind, Result};
+
+/*
+An incremental multipart parser. Input is pulled from `R` in whatever pieces it
+comes, down to single bytes, and kept in a buffer only until it's known not to
+belong to a boundary.
+
+    preamble            Preamble
+    --boundary          Boundary
+    Header: value       (part header)
+                        Body
+    content
+    --boundary--        Done
+    epilogue
+
+A boundary line ends with CRLF or a bare LF, the first one decides which line
+break the delimiters in front of the following boundaries have. Text before the
+first and after the last boundary is ignored.
+
+Input goes into one buffer allocated up front. Consumed bytes are reclaimed by
+moving what's left (usually less than a delimiter) to the front once the end of
+the buffer is reached. Delimiters are searched with memchr's SIMD substring search
+(`cargo bench --bench multipart`).
+*/
+
+// Input buffer, grown to hold at least a whole part header
+const BUFFER_SIZE: usize = 64 << 10;
+
+#[derive(PartialEq)]
+pub(super) enum State {
+    // Before the first boundary
+    Preamble,
+    // In a part body, until the next delimiter
+    Body,
+    // Right behind a boundary, either a part or the end follows
+    Boundary,
+    Done,
+}
+
+pub(super) struct Parser<R> {
+    input: R,
+    // Unconsumed input is buf[start..end]
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    // Bytes at the start of the input known to be body content
+    clear: usize,
+    // "--boundary"
+    dash_boundary: Vec<u8>,
+    // Line break + "--boundary", known after the first boundary line
+    delimiter: Option<Finder<'static>>,
+    // Largest header block of a part
+    max_header_size: usize,
+    pub(super) state: State,
+    pub(super) parts_read: usize,
+}
+
+impl<R: Read> Parser<R> {
+    pub(super) fn new(input: R, boundary: &str, max_header_size: usize) -> Self {
+        let mut dash_boundary = b"--".to_vec();
+        dash_boundary.extend_from_slice(boundary.trim_matches('"').as_bytes());
+
+        // A line break in front lets a boundary on the very first line be found
+        // like any other
+        let size = BUFFER_SIZE.max(max_header_size + 1);
+        let mut buf = vec![0; size].into_boxed_slice();
+        buf[0] = b'\n';
+
+        Self {
+            input,
+            buf,
+            start: 0,
+            end: 1,
+            clear: 0,
+            dash_boundary,
+            delimiter: None,
+            max_header_size,
+            state: State::Preamble,
+            parts_read: 0,
+        }
+    }
+
+    // Move to the header of the next part, None after the last one
+    pub(super) fn next_part(&mut self) -> Result<Option<HeaderMap>> {
+        match self.state {
+            State::Preamble => self.skip_preamble()?,
+            // Skip the rest of the current part
+            State::Body => {
+                io::copy(&mut BodyOf(self), &mut io::sink())?;
+            }
+            State::Boundary => (),
+            State::Done => return Ok(None),
+        }
+
+        // "--" closes the body, anything else must be a line break
+        self.fill_to(2)?;
+        if self.data().starts_with(b"--") {
+            self.state = State::Done;
+            // Ignore the epilogue
+            io::copy(&mut self.input, &mut io::sink())?;
+            return Ok(None);
+        }
+        self.skip_line_break()?;
+
+        let header = self.read_header()?;
+        self.parts_read += 1;
+        self.state = State::Body;
+        Ok(Some(header))
+    }
+
+    // Body bytes up to the next delimiter, 0 once it's reached
+    pub(super) fn read_body(&mut self, out: &mut [u8]) -> Result<usize> {
+        if self.state != State::Body || out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if self.clear == 0 {
+                let delimiter = self.delimiter.as_ref().unwrap();
+                match scan(&self.buf[self.start..self.end], delimiter) {
+                    Scan::Delimiter(0) => {
+                        self.start += delimiter.needle().len();
+                        self.state = State::Boundary;
+                        return Ok(0);
+                    }
+                    Scan::Delimiter(i) | Scan::Clear(i) => self.clear = i,
+                }
+            }
+
+            if self.clear > 0 {
+                let n = self.clear.min(out.len());
+                out[..n].copy_from_slice(&self.data()[..n]);
+                self.start += n;
+                self.clear -= n;
+                return Ok(n);
+            }
+
+            if self.fill()? == 0 {
+                return Err(truncated());
+            }
+        }
+    }
+
+    fn skip_preamble(&mut self) -> Result<()> {
+        let line_dash_boundary =
+            Finder::new(&[b"\n", &self.dash_boundary[..]].concat()).into_owned();
+
+        loop {
+            match scan(self.data(), &line_dash_boundary) {
+                Scan::Delimiter(i) => {
+                    self.start += i + line_dash_boundary.needle().len();
+                    self.state = State::Boundary;
+                    return Ok(());
+                }
+                Scan::Clear(i) => self.start += i,
+            }
+            if self.fill()? == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "no multipart boundary found",
+                ));
+            }
+        }
+    }
+
+    // Optional whitespace and the line break ending a boundary line
+    fn skip_line_break(&mut self) -> Result<()> {
+        loop {
+            let data = self.data();
+            let padding = data
+                .iter()
+                .take_while(|&&c| c == b' ' || c == b'\t')
+                .count();
+            let nl: &[u8] = match &data[padding..] {
+                [b'\r', b'\n', ..] => b"\r\n",
+                [b'\n', ..] => b"\n",
+                [] | [b'\r'] if padding < self.max_header_size => {
+                    if self.fill()? == 0 {
+                        return Err(truncated());
+                    }
+                    continue;
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "invalid multipart boundary",
+                    ))
+                }
+            };
+
+            if self.delimiter.is_none() {
+                let delimiter = [nl, &self.dash_boundary].concat();
+                self.delimiter = Some(Finder::new(&delimiter).into_owned());
+            }
+            self.start += padding + nl.len();
+            return Ok(());
+        }
+    }
+
+    // The header lines of a part, up to the empty line
+    fn read_header(&mut self) -> Result<HeaderMap> {
+        let mut size = 0;
+        // Lines starting with whitespace continue the previous field (obsolete folding)
+        let mut fields: Vec<(String, String)> = Vec::new();
+        loop {
+            let line = match memchr::memchr(b'\n', self.data()) {
+                Some(i) => {
+                    let line = String::from_utf8_lossy(&self.data()[..i]).to_string();
+                    self.start += i + 1;
+                    size += i + 1;
+                    line
+                }
+                None if size + self.data().len() > self.max_header_size => {
+                    return Err(self.header_too_large());
+                }
+                None => {
+                    if self.fill()? == 0 {
+                        return Err(truncated());
+                    }
+                    continue;
+                }
+            };
+            if size > self.max_header_size {
+                return Err(self.header_too_large());
+            }
+
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                    continue;
+                }
+            }
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid part header: {:?}", line),
+                )
+            })?;
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut header = HeaderMap::new();
+        for (name, value) in fields {
+            header
+                .append(&name, &value)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        }
+        Ok(header)
+    }
+
+    fn header_too_large(&self) -> Error {
+        LimitError::HeaderTooLarge {
+            limit: self.max_header_size,
+        }
+        .into()
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    // Read more input, 0 at its end
+    fn fill(&mut self) -> Result<usize> {
+        if self.end == self.buf.len() {
+            if self.start == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "multipart buffer is full",
+                ));
+            }
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        let n = self.input.read(&mut self.buf[self.end..])?;
+        self.end += n;
+        Ok(n)
+    }
+
+    fn fill_to(&mut self, size: usize) -> Result<()> {
+        while self.data().len() < size {
+            if self.fill()? == 0 {
+                return Err(truncated());
+            }
+        }
+        Ok(())
+    }
+}
+
+// `Read` over the current part body for `io::copy`
+struct BodyOf<'p, R>(&'p mut Parser<R>);
+
+impl<R: Read> Read for BodyOf<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read_body(buf)
+    }
+}
+
+enum Scan {
+    // A delimiter starts here
+    Delimiter(usize),
+    // No delimiter starts before this
+    Clear(usize),
+}
+
+// The boundary may not be followed by anything but "--", whitespace or a line
+// break, otherwise it's just content that happens to start like one
+fn scan(data: &[u8], delimiter: &Finder) -> Scan {
+    let len = delimiter.needle().len();
+    let mut from = 0;
+    while let Some(i) = delimiter.find(&data[from..]).map(|i| i + from) {
+        match data.get(i + len) {
+            // The next byte tells
+            None => return Scan::Clear(i),
+            Some(b'-' | b' ' | b'\t' | b'\r' | b'\n') => return Scan::Delimiter(i),
+            Some(_) => from = i + 1,
+        }
+    }
+    // The end may be the beginning of a delimiter
+    Scan::Clear(data.len().saturating_sub(len - 1))
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "multipart body truncated")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out the input in pieces of 1..=max bytes, sizes from a xorshift sequence
+    struct Chunks {
+        data: Vec<u8>,
+        pos: usize,
+        seed: u64,
+        max: usize,
+    }
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = (1 + next(&mut self.seed) as usize % self.max)
+                .min(buf.len())
+                .min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    fn next(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    // Content-Disposition and body of every part
+    fn parse(data: &[u8], boundary: &str, max: usize, seed: u64) -> Result<Vec<(String, Vec<u8>)>> {
+        let input = Chunks {
+            data: data.to_vec(),
+            pos: 0,
+            seed,
+            max,
+        };
+        let mut parser = Parser::new(input, boundary, 16 << 10);
+        let mut parts = Vec::new();
+        while let Some(header) = parser.next_part()? {
+            let mut body = Vec::new();
+            let mut buf = vec![0; 1 + seed as usize % 7];
+            loop {
+                let n = parser.read_body(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                body.extend_from_slice(&buf[..n]);
+            }
+            let disposition = header.get("Content-Disposition").unwrap_or("");
+            parts.push((disposition.to_string(), body));
+        }
+        Ok(parts)
+    }
+
+    // The same parts for every way of splitting the input as for no splitting at all
+    fn assert_chunking_irrelevant(
+        data: &[u8],
+        boundary: &str,
+        seed: u64,
+    ) -> Vec<(String, Vec<u8>)> {
+        let whole = parse(data, boundary, data.len().max(1), seed).unwrap();
+        for max in [1, 2, 3, 7, 64, 4096] {
+            let parts = parse(data, boundary, max, seed).unwrap();
+            assert!(
+                parts == whole,
+                "parts differ with chunks up to {} bytes",
+                max
+            );
+        }
+        whole
+    }
+
+    // A body with `count` parts whose content looks a lot like delimiters
+    fn body(seed: &mut u64, nl: &str, count: usize) -> (Vec<u8>, Vec<(String, Vec<u8>)>) {
+        let b = "xB-9";
+        let mut data = Vec::new();
+        let mut expected = Vec::new();
+        if next(seed).is_multiple_of(2) {
+            data.extend_from_slice(format!("pre--{b}amble{nl}--{b}X{nl}").as_bytes());
+        }
+        for i in 0..count {
+            let padding = if next(seed).is_multiple_of(3) {
+                " \t"
+            } else {
+                ""
+            };
+            let disposition = format!("form-data; name=\"f{i}\"");
+            data.extend_from_slice(format!("--{b}{padding}{nl}").as_bytes());
+            data.extend_from_slice(
+                format!("Content-Disposition: {disposition}{nl}{nl}").as_bytes(),
+            );
+
+            let alphabet = b"\r\n-xB9a";
+            let len = next(seed) as usize % 300;
+            let mut content: Vec<u8> = (0..len)
+                .map(|_| alphabet[next(seed) as usize % alphabet.len()])
+                .collect();
+            if next(seed).is_multiple_of(2) {
+                content.extend_from_slice(format!("{nl}--{b}Z--").as_bytes());
+            }
+            // Content mustn't contain a real delimiter or end in half of a CRLF
+            let text = String::from_utf8(content.clone()).unwrap();
+            if text.contains(&format!("{nl}--{b}")) || (nl == "\r\n" && text.ends_with('\r')) {
+                content = b"plain".to_vec();
+            }
+            data.extend_from_slice(&content);
+            data.extend_from_slice(nl.as_bytes());
+            expected.push((disposition, content));
+        }
+        // No line break after the close delimiter unless there's an epilogue
+        data.extend_from_slice(format!("--{b}--").as_bytes());
+        if next(seed).is_multiple_of(2) {
+            data.extend_from_slice(format!("{nl}epilogue --{b}{nl}").as_bytes());
+        }
+        (data, expected)
+    }
+
+    #[test]
+    fn random_chunk_sizes() {
+        let mut seed = 0x9e3779b97f4a7c15;
+        for round in 0..500 {
+            let nl = if round % 2 == 0 { "\r\n" } else { "\n" };
+            let (data, expected) = body(&mut seed, nl, round % 5);
+            assert!(assert_chunking_irrelevant(&data, "xB-9", seed) == expected);
+        }
+    }
+
+    #[test]
+    fn part_larger_than_buffer() {
+        let content: Vec<u8> = (0..3 * BUFFER_SIZE).map(|i| b"ab\r\n-"[i % 5]).collect();
+        let mut data = b"--b\r\nContent-Disposition: form-data; name=\"f\"\r\n\r\n".to_vec();
+        data.extend_from_slice(&content);
+        data.extend_from_slice(b"\r\n--b--\r\n");
+
+        let parts = assert_chunking_irrelevant(&data, "b", 7);
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].1 == content);
+    }
+
+    #[test]
+    fn truncated() {
+        let inputs: [&[u8]; 5] = [
+            b"--b\r\nA: b\r\n\r\nxx",
+            b"--b\r\nA: b\r\n\r\nxx\r\n--b",
+            b"--b\r\nA: b",
+            b"--b",
+            b"no boundary at all",
+        ];
+        for data in inputs {
+            for max in [1, 2, 3, data.len()] {
+                assert!(parse(data, "b", max, 3).is_err(), "{:?}", data);
+            }
+        }
+    }
+
+    #[test]
+    fn quoted_boundary_and_no_parts() {
+        let parts = assert_chunking_irrelevant(b"--b--", "\"b\"", 1);
+        assert!(parts.is_empty());
+    }
+}
@@ -1,12 +1,23 @@
 use std::{
     collections::HashMap,
     io::{prelude::*, BufReader, Error, ErrorKind, Result},
-    net::{SocketAddr, TcpStream},
+    net::SocketAddr,
     str,
+    sync::OnceLock,
 };
 
 use crate::content_type::ContentType;
-use crate::request::mime::multipart::{MultiPart, Part};
+use crate::cookie::{parse_cookie_header, secure};
+use crate::header::HeaderMap;
+use crate::request::digest::BodyDigests;
+use crate::request::extract::ExtractError;
+use crate::request::json::JsonError;
+use crate::request::limits::{LimitError, MultipartLimits};
+use crate::request::mime::formdata::{FormFile, DEFAULT_MAX_MEMORY};
+use crate::request::mime::multipart::MultiPart;
+use crate::request::query::QueryMap;
+use crate::stream::Stream;
+use serde::de::DeserializeOwned;
 
 /*
 POST /hello HTTP/1.1
@@ -20,8 +31,18 @@ name=John&Doe&age=30
 
 const MAX_PARSE_BODY_SIZE: usize = 10 << 20; // 10MB
 
+// Body limit of routes without their own, see `RustWeb::body_limit`
+static BODY_LIMIT: OnceLock<usize> = OnceLock::new();
+
+// Only once, a second call panics
+pub fn set_body_limit(limit: usize) {
+    if BODY_LIMIT.set(limit).is_err() {
+        panic!("body limit is already set");
+    }
+}
+
 const METHODS: &[&str] = &[
-    "GET", "POST", "PUT", "DELETE", "OPTIONS", "HEAD", "TRACE", "CONNECT",
+    "GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "HEAD", "TRACE", "CONNECT",
 ];
 
 /*
@@ -38,33 +59,94 @@ pub struct Request {
     pub method: String,
     pub uri: String,
     pub path: String,
-    pub query: HashMap<String, String>,
+    pub query: QueryMap,
+    // Values of `:name` segments in the matched route
+    pub params: QueryMap,
     pub version: String,
-    pub headers: HashMap<String, Vec<String>>,
+    pub headers: HeaderMap,
     pub content_type: ContentType,
     pub content_length: usize,
+    // Largest body accepted for this request
+    pub body_limit: usize,
+    pub multipart_limits: MultipartLimits,
+    body_read: usize,
+    // Body framing state, see `body_reader`
+    chunked: bool,
+    chunk_remaining: usize,
+    body_done: bool,
+    // Progress of the body is recorded under this, see `progress`
+    upload_id: Option<String>,
+    // Digests of the body, checked against the ones sent, see `digest`
+    digests: BodyDigests,
     boundary: String,
     pub body: Vec<u8>,
     pub form: HashMap<String, Vec<String>>,
     pub post_form: HashMap<String, Vec<String>>,
-    pub reader: BufReader<TcpStream>,
-    multipart: MultiPart,
+    // Files of a multipart form, see `parse_multipart_form`
+    pub files: HashMap<String, Vec<FormFile>>,
+    multipart_parsed: bool,
+    pub reader: BufReader<Stream>,
+    // Where the body is read from once an async handler took the request over,
+    // see `async_body`
+    #[cfg(feature = "async")]
+    async_reader: Option<tokio::io::BufReader<crate::stream::AsyncStream>>,
 }
 
+#[cfg(feature = "async")]
+pub mod async_body;
+pub mod body;
+pub mod digest;
+pub mod extract;
+pub mod json;
+pub mod limits;
 pub mod mime;
+pub mod progress;
+pub mod query;
+pub mod upload;
 
 impl Request {
+    // Header names are case-insensitive
     pub fn header(&self, key: &str) -> Vec<String> {
-        self.headers.get(key).unwrap_or(&Vec::new()).to_vec()
+        self.headers
+            .get_all(key)
+            .into_iter()
+            .map(|value| value.to_string())
+            .collect()
     }
 
     pub fn header_first(&self, key: &str) -> String {
-        match self.headers.get(key) {
-            Some(values) if values.len() > 0 => values[0].to_string(),
-            _ => "".to_string(),
-        }
+        self.headers.get(key).unwrap_or("").to_string()
+    }
+
+    // The value of the cookie sent in the `Cookie` header, empty if absent
+    pub fn cookie(&self, name: &str) -> String {
+        self.cookies()
+            .into_iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v)
+            .unwrap_or_default()
+    }
+
+    // The value of a cookie set with `add_signed_cookie`. A tampered cookie is
+    // treated as absent.
+    pub fn signed_cookie(&self, name: &str) -> String {
+        secure::verify(name, &self.cookie(name)).unwrap_or_default()
+    }
+
+    // The value of a cookie set with `add_private_cookie`, empty if absent or tampered
+    pub fn private_cookie(&self, name: &str) -> String {
+        secure::decrypt(name, &self.cookie(name)).unwrap_or_default()
+    }
+
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        self.headers
+            .get_all("Cookie")
+            .into_iter()
+            .flat_map(parse_cookie_header)
+            .collect()
     }
 
+    // The first value of the query parameter, empty if absent
     pub fn query(&self, key: &str) -> String {
         match self.query.get(key) {
             None => "".to_string(),
@@ -72,23 +154,82 @@ impl Request {
         }
     }
 
-    pub fn new(reader: BufReader<TcpStream>, address: SocketAddr) -> Request {
+    pub fn query_all(&self, key: &str) -> Vec<String> {
+        self.query
+            .get_all(key)
+            .into_iter()
+            .map(|value| value.to_string())
+            .collect()
+    }
+
+    // Whether the parameter was sent at all, e.g. a flag like `?debug`
+    pub fn has_query(&self, key: &str) -> bool {
+        self.query.contains_key(key)
+    }
+
+    pub fn param(&self, key: &str) -> String {
+        self.params.get(key).unwrap_or("").to_string()
+    }
+
+    // Deserialize the query parameters into `T`, see `extract::extract`
+    pub fn query_as<T: DeserializeOwned>(&self) -> std::result::Result<T, ExtractError> {
+        extract::extract(self.query.iter())
+    }
+
+    pub fn params_as<T: DeserializeOwned>(&self) -> std::result::Result<T, ExtractError> {
+        extract::extract(self.params.iter())
+    }
+
+    // Deserialize the urlencoded body into `T`, parsing it first if needed
+    pub fn form_as<T: DeserializeOwned>(&mut self) -> std::result::Result<T, ExtractError> {
+        // Multipart forms may be parsed with no text fields at all
+        let parsed = match self.content_type {
+            ContentType::MultiPart { .. } => self.parse_multipart_form(DEFAULT_MAX_MEMORY),
+            _ if self.post_form.is_empty() => self.parse_post_form(),
+            _ => Ok(()),
+        };
+        if let Err(err) = parsed {
+            return Err(ExtractError {
+                missing: vec![],
+                invalid: vec![("".to_string(), err.to_string())],
+            });
+        }
+        let pairs = self
+            .post_form
+            .iter()
+            .flat_map(|(k, values)| values.iter().map(move |v| (k.as_str(), v.as_str())));
+        extract::extract(pairs)
+    }
+
+    pub fn new(reader: BufReader<Stream>, address: SocketAddr) -> Request {
         Request {
             address,
             method: "".to_string(),
             uri: "".to_string(),
             path: "".to_string(),
-            query: HashMap::new(),
+            query: QueryMap::new(),
+            params: QueryMap::new(),
             version: "".to_string(),
-            headers: HashMap::new(),
+            headers: HeaderMap::new(),
             content_type: ContentType::None,
             content_length: 0,
+            body_limit: BODY_LIMIT.get().copied().unwrap_or(MAX_PARSE_BODY_SIZE),
+            multipart_limits: limits::multipart_limits(),
+            body_read: 0,
+            chunked: false,
+            chunk_remaining: 0,
+            body_done: false,
+            upload_id: None,
+            digests: BodyDigests::default(),
             boundary: "".to_string(),
             body: vec![],
             form: HashMap::new(),
             post_form: HashMap::new(),
+            files: HashMap::new(),
+            multipart_parsed: false,
             reader,
-            multipart: MultiPart::new("", 0),
+            #[cfg(feature = "async")]
+            async_reader: None,
         }
     }
 
@@ -123,7 +264,7 @@ impl Request {
         }
         */
 
-        let (method, uri, path, queries, version) = parse_request_line(&request_line);
+        let (method, uri, path, queries, version) = parse_request_line(&request_line)?;
         if !METHODS.contains(&&method[..]) {
             return Err(Error::new(
                 ErrorKind::Unsupported,
@@ -165,9 +306,20 @@ impl Request {
         self.path = path;
         self.version = version;
         self.query = queries;
-        self.headers = parse_request_header(&header);
+        self.headers = parse_request_header(&header)?;
         self.content_type = ContentType::parse(&&self.header_first("Content-Type"));
         self.content_length = self.header_first("Content-Length").parse().unwrap_or(0);
+        // Chunked takes precedence over Content-Length
+        self.chunked = self
+            .header("Transfer-Encoding")
+            .iter()
+            .flat_map(|value| value.split(','))
+            .any(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        self.upload_id = progress::upload_id(
+            &self.header_first(progress::UPLOAD_ID_HEADER),
+            &self.query(progress::UPLOAD_ID_PARAM),
+        );
+        self.digests = BodyDigests::from_headers(&self.headers)?;
 
         match &self.content_type {
             ContentType::MultiPart {
@@ -180,151 +332,262 @@ impl Request {
         Ok(())
     }
 
-    pub fn body(&mut self) -> Vec<u8> {
-        let length = self.content_length;
-        if length <= 0 {
-            return vec![];
-        }
-
-        if length > MAX_PARSE_BODY_SIZE {
-            println!("Body too large!");
-            return vec![];
+    // The whole body, kept for later calls. A body over the limit fails with a
+    // `LimitError`, a truncated one with the read error, never with part of it.
+    pub fn body(&mut self) -> Result<Vec<u8>> {
+        if self.content_length > self.body_limit {
+            return Err(LimitError::BodyTooLarge {
+                limit: self.body_limit,
+            }
+            .into());
         }
 
         if self.body.len() > 0 {
-            return self.body.to_vec();
+            return Ok(self.body.to_vec());
         }
 
-        let mut buf = [0; 4096];
-        let mut bytes_remaining = length;
-
-        while bytes_remaining > 0 {
-            match self.reader.read(&mut buf) {
-                Ok(0) => break,
-                Ok(n) => {
-                    self.body.extend_from_slice(&buf[0..n]);
-                    bytes_remaining -= n;
-                }
-                Err(e) => {
-                    eprintln!("Error while reading from stream: {}", e);
-                    break;
-                }
-            }
+        // Errors as described at `body_reader`, e.g. a truncated body
+        let mut body = Vec::new();
+        self.body_reader().read_to_end(&mut body)?;
+        self.body = body;
+
+        Ok(self.body.to_vec())
+    }
+
+    // Deserialize a JSON body. Fails with 415 unless the Content-Type is JSON, with
+    // 413 when the body is too large and with 400 (and the error position) when
+    // the body doesn't parse as `T`.
+    pub fn json<T: DeserializeOwned>(&mut self) -> std::result::Result<T, JsonError> {
+        let is_json = matches!(
+            &self.content_type,
+            ContentType::Application(sub_type) if sub_type == "json" || sub_type.ends_with("+json")
+        );
+        if !is_json {
+            return Err(JsonError::UnsupportedMediaType(
+                self.header_first("Content-Type"),
+            ));
         }
 
-        // println!("content length: {length}");
-        // println!("bytes remaining: {bytes_remaining}");
-        // println!("body length: {}", self.body.len());
+        if self.content_length > self.body_limit {
+            return Err(JsonError::ContentTooLarge {
+                length: self.content_length,
+                limit: self.body_limit,
+            });
+        }
 
-        self.body.to_vec()
+        let body = match self.body() {
+            Ok(body) => body,
+            // A chunked body only turns out too large while it's read
+            Err(err) if LimitError::of(&err).is_some() => {
+                return Err(JsonError::ContentTooLarge {
+                    length: self.content_length.max(self.body_read),
+                    limit: self.body_limit,
+                })
+            }
+            Err(err) => return Err(JsonError::Body(err.to_string())),
+        };
+        Ok(serde_json::from_slice(&body)?)
     }
 
+    // Read the next piece of the body, 0 at its end. See `body_reader` for the errors.
     pub fn read_body(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
-        self.reader.read(buf)
+        self.read_framed(buf)
     }
 
-    pub fn parse_post_form(&mut self) {
-        if self.method != "POST" {
-            return;
+    // Only POST, PUT and PATCH bodies are parsed. Fails with `ErrorKind::InvalidData`
+    // when the body is not valid urlencoded UTF-8
+    pub fn parse_post_form(&mut self) -> Result<()> {
+        if !matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") {
+            return Ok(());
         }
 
         let body = if self.body.len() > 0 {
             self.body.to_vec()
         } else {
-            self.body()
+            self.body()?
         };
 
         match String::from_utf8(body) {
             Ok(content) => parse_form(&content, &mut self.post_form),
-            Err(e) => println!("Error occurred when parse_post_form: {}", e),
-        }
-    }
-
-    pub fn multipart<'a>(&'a mut self) -> &'a mut Self {
-        if self.method == "POST" {
-            self.multipart = MultiPart::new(&self.boundary, self.content_length);
-        }
-        self
-    }
-
-    pub fn next(&mut self) -> Option<&Part> {
-        if self.method == "POST" {
-            self.multipart.next(&mut self.reader)
-        } else {
-            None
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
         }
     }
 
-    pub fn part_body(&mut self) -> Result<Vec<u8>> {
-        if self.method == "POST" {
-            let multipart_reader = &mut self.multipart;
-            multipart_reader.body(&mut self.reader)
-        } else {
-            Err(Error::new(
-                ErrorKind::Unsupported,
-                format!("there is no part, method: {}", self.method),
-            ))
-        }
+    // The parts of a multipart body, each streamed from the connection as it's read
+    pub fn multipart(&mut self) -> MultiPart<'_> {
+        let boundary = self.boundary.clone();
+        let limits = self.multipart_limits.clone();
+        MultiPart::new(self.body_reader(), &boundary, limits)
     }
 }
 
 //POST /hello?name=sfdex&age=18 HTTP/1.1
-fn parse_request_line(
-    request_line: &str,
-) -> (String, String, String, HashMap<String, String>, String) {
+fn parse_request_line(request_line: &str) -> Result<(String, String, String, QueryMap, String)> {
     let v: Vec<&str> = request_line.split_whitespace().collect();
 
     let uri = v[1];
-    let mut queries: HashMap<String, String> = HashMap::new();
-    let mut path = uri.to_string();
+    let mut queries = QueryMap::new();
+    let (raw_path, raw_queries) = uri.split_once("?").unwrap_or((uri, ""));
 
-    // parse queries
-    if let Some((path_in_uri, queries_str)) = uri.split_once("?") {
-        path = path_in_uri.to_string();
-        parse_query(queries_str, &mut queries);
-    }
-    (
+    let path = decode_path(raw_path)?;
+    parse_query(raw_queries, &mut queries)?;
+
+    Ok((
         v[0].to_string(),
         uri.to_string(),
         path,
         queries,
         v[2].to_string(),
-    )
+    ))
 }
 
-fn parse_request_header(request_header: &str) -> HashMap<String, Vec<String>> {
+fn parse_request_header(request_header: &str) -> Result<HeaderMap> {
     let mut headers = request_header.lines();
     headers.next(); // skip the first empty line
 
-    let mut headers_map: HashMap<String, Vec<String>> = HashMap::new();
+    let mut headers_map = HeaderMap::new();
     for header in headers {
         let mut parts = header.splitn(2, ":");
         if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
-            let values = headers_map.entry(k.to_string()).or_insert(vec![]);
-            values.push(v.trim().to_string());
+            // No whitespace is allowed between the name and ':' (RFC 9112)
+            headers_map
+                .append(k, v.trim())
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
         }
     }
 
-    headers_map
+    Ok(headers_map)
 }
 
-fn parse_form(forms: &str, map: &mut HashMap<String, Vec<String>>) {
+fn parse_form(forms: &str, map: &mut HashMap<String, Vec<String>>) -> Result<()> {
     for pair in forms.split("&") {
         let mut parts = pair.splitn(2, "=");
 
         if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
-            let values = map.entry(k.to_string()).or_insert(vec![]);
-            values.push(v.trim().to_string());
+            let values = map.entry(percent_decode(k, true)?).or_default();
+            values.push(percent_decode(v.trim(), true)?);
         }
     }
+    Ok(())
 }
 
-fn parse_query(query: &str, map: &mut HashMap<String, String>) {
-    //let mut map: HashMap<String, String> = HashMap::new();
+fn parse_query(query: &str, map: &mut QueryMap) -> Result<()> {
     for pair in query.split("&") {
-        let mut parts = pair.splitn(2, "=");
-        if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
-            map.insert(k.trim().to_string(), v.trim().to_string());
+        if pair.is_empty() {
+            continue;
+        }
+        // A key without '=' is a flag with an empty value
+        let (k, v) = pair.split_once("=").unwrap_or((pair, ""));
+        map.append(
+            percent_decode(k.trim(), true)?,
+            percent_decode(v.trim(), true)?,
+        );
+    }
+    Ok(())
+}
+
+// Decode each path segment on its own. An encoded '/' would turn into a separator
+// after decoding and let "/a%2Fb" be routed as "/a/b", so it is rejected.
+fn decode_path(path: &str) -> Result<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        let decoded = percent_decode(segment, false)?;
+        if decoded.contains('/') {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "encoded '/' in path segment",
+            ));
+        }
+        segments.push(decoded);
+    }
+    Ok(segments.join("/"))
+}
+
+// RFC 3986 percent-decoding, with '+' meaning space in urlencoded forms and queries
+fn percent_decode(input: &str, plus_as_space: bool) -> Result<String> {
+    let decoded = percent_decode_bytes(input, plus_as_space)?;
+    String::from_utf8(decoded).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("percent-encoded bytes are not UTF-8: {}", input),
+        )
+    })
+}
+
+fn percent_decode_bytes(input: &str, plus_as_space: bool) -> Result<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let (hi, lo) = match (bytes.get(i + 1), bytes.get(i + 2)) {
+                    (Some(&hi), Some(&lo)) if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                        (hi, lo)
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("invalid percent-encoding: {}", input),
+                        ))
+                    }
+                };
+                decoded.push(hex_value(hi) << 4 | hex_value(lo));
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            c => {
+                decoded.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+fn hex_value(c: u8) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        _ => c - b'A' + 10,
+    }
+}
+
+// A request read from `raw` for tests, the connection ends after it
+#[cfg(test)]
+pub(crate) fn from_raw(raw: &[u8]) -> Request {
+    crate::context::from_raw(raw).request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("%41%62c%2b%2B", true).unwrap(), "Abc++");
+        assert_eq!(percent_decode("a+b%20c", true).unwrap(), "a b c");
+        assert_eq!(percent_decode("a+b", false).unwrap(), "a+b");
+        assert_eq!(percent_decode("%E2%82%AC", false).unwrap(), "€");
+
+        for input in ["%", "%4", "%zz", "a%g1", "%%41"] {
+            let err = percent_decode(input, true).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", input);
         }
+        // Valid escapes, but not UTF-8
+        assert!(percent_decode("%ff%fe", true).is_err());
+        assert_eq!(percent_decode_bytes("%ff", false).unwrap(), [0xff]);
+    }
+
+    #[test]
+    fn path_decoding() {
+        assert_eq!(decode_path("/a%20b/c+d").unwrap(), "/a b/c+d");
+        assert_eq!(decode_path("/files/%E2%82%AC").unwrap(), "/files/€");
+        assert!(decode_path("/a%2Fb").is_err());
+        assert!(decode_path("/a%2fb").is_err());
+        assert!(decode_path("/a%2").is_err());
     }
 }
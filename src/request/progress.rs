@@ -0,0 +1,175 @@
+use crate::context::{Context, ContextFn};
+use crate::response::status::Status;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/*
+Progress of uploads, for clients showing a progress bar.
+
+The client picks an id for the upload and sends it in the `X-Upload-ID` header or
+the `upload_id` query parameter. While the body is read, however the handler reads
+it, the bytes received so far are recorded under that id.
+
+    web.upload_progress("/progress");
+    // GET /progress?upload_id=abc -> {"received":1048576,"total":52428800,"done":false}
+
+    web.on_upload_progress(|id, progress| println!("{}: {} bytes", id, progress.received));
+
+Nothing is tracked unless one of the two is used. An entry is dropped a minute
+after its last update, whether the upload completed or was abandoned.
+*/
+
+pub const UPLOAD_ID_HEADER: &str = "X-Upload-ID";
+pub const UPLOAD_ID_PARAM: &str = "upload_id";
+
+const EXPIRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadProgress {
+    pub received: u64,
+    // None for chunked bodies
+    pub total: Option<u64>,
+    // The whole body was read
+    pub done: bool,
+}
+
+pub type ProgressHook = fn(&str, &UploadProgress);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static UPLOADS: OnceLock<Mutex<HashMap<String, (UploadProgress, Instant)>>> = OnceLock::new();
+static HOOK: OnceLock<ProgressHook> = OnceLock::new();
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+// Only one hook, a second call panics
+pub fn set_hook(hook: ProgressHook) {
+    if HOOK.set(hook).is_err() {
+        panic!("upload progress hook is already set");
+    }
+    enable();
+}
+
+fn uploads() -> &'static Mutex<HashMap<String, (UploadProgress, Instant)>> {
+    UPLOADS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// The progress of an upload still known
+pub fn get(id: &str) -> Option<UploadProgress> {
+    let uploads = uploads().lock().unwrap();
+    match uploads.get(id) {
+        Some((progress, updated)) if updated.elapsed() < EXPIRY => Some(progress.clone()),
+        _ => None,
+    }
+}
+
+// The upload id of a request, if progress is tracked at all. Ids are client input,
+// only short ones made of letters, digits, '-', '_' and '.' are accepted.
+pub(crate) fn upload_id(header: &str, query: &str) -> Option<String> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return None;
+    }
+    let id = if header.is_empty() { query } else { header };
+    let valid = !id.is_empty()
+        && id.len() <= 128
+        && id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'));
+    valid.then(|| id.to_string())
+}
+
+pub(crate) fn update(id: &str, progress: UploadProgress) {
+    {
+        let mut uploads = uploads().lock().unwrap();
+        if !uploads.contains_key(id) {
+            uploads.retain(|_, (_, updated)| updated.elapsed() < EXPIRY);
+        }
+        uploads.insert(id.to_string(), (progress.clone(), Instant::now()));
+    }
+    // Not under the lock, the hook may look up other uploads
+    if let Some(hook) = HOOK.get() {
+        hook(id, &progress);
+    }
+}
+
+// The endpoint registered by `RustWeb::upload_progress`, 404 for unknown ids
+pub(crate) fn handler(mut c: Context) {
+    let mut id = c.request.header_first(UPLOAD_ID_HEADER);
+    if id.is_empty() {
+        id = c.request.query(UPLOAD_ID_PARAM);
+    }
+    match get(&id) {
+        Some(progress) => c.json(&progress),
+        None => c.error_with_status(Status::NotFound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::from_raw;
+
+    #[test]
+    fn upload_ids() {
+        enable();
+        assert_eq!(
+            upload_id("abc-1_2.z", "other").as_deref(),
+            Some("abc-1_2.z")
+        );
+        assert_eq!(upload_id("", "from-query").as_deref(), Some("from-query"));
+        for id in ["", "a b", "../x", "ü", &"a".repeat(129)] {
+            assert_eq!(upload_id(id, ""), None, "{:?}", id);
+        }
+        assert!(upload_id(&"a".repeat(128), "").is_some());
+    }
+
+    #[test]
+    fn tracks_reads() {
+        enable();
+        let mut request =
+            from_raw(b"POST /?upload_id=progress-sized HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(get("progress-sized").is_none());
+        request.body().unwrap();
+        let progress = get("progress-sized").unwrap();
+        assert_eq!((progress.received, progress.total), (5, Some(5)));
+        assert!(progress.done);
+
+        let mut request = from_raw(
+            b"POST / HTTP/1.1\r\nX-Upload-ID: progress-chunked\r\n\
+              Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n",
+        );
+        assert!(request.body().is_err());
+        let progress = get("progress-chunked").unwrap();
+        assert_eq!((progress.received, progress.total), (3, None));
+        assert!(!progress.done);
+    }
+
+    #[test]
+    fn old_entries_are_cleared() {
+        let long_ago = Instant::now().checked_sub(EXPIRY * 2).unwrap();
+        let progress = UploadProgress {
+            received: 1,
+            total: None,
+            done: false,
+        };
+        uploads()
+            .lock()
+            .unwrap()
+            .insert("progress-old".to_string(), (progress.clone(), long_ago));
+        assert!(get("progress-old").is_none());
+
+        update("progress-old", progress.clone());
+        assert!(get("progress-old").is_some());
+
+        // A new one drops expired entries
+        uploads().lock().unwrap().get_mut("progress-old").unwrap().1 = long_ago;
+        update("progress-new", progress);
+        let uploads = uploads().lock().unwrap();
+        assert!(!uploads.contains_key("progress-old"));
+        assert!(uploads.contains_key("progress-new"));
+    }
+}
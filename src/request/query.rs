@@ -0,0 +1,54 @@
+/*
+Query parameters in the order they appear in the URI.
+
+?tag=a&tag=b&debug  =>  [("tag", "a"), ("tag", "b"), ("debug", "")]
+
+A key may appear several times, and a key without '=' (a flag) has an empty value.
+*/
+#[derive(Debug, Default, Clone)]
+pub struct QueryMap {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryMap {
+    pub fn new() -> Self {
+        Self { pairs: Vec::new() }
+    }
+
+    pub fn append(&mut self, key: String, value: String) {
+        self.pairs.push((key, value));
+    }
+
+    // The first value of the key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // All values of the key, in order
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.pairs
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.pairs.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
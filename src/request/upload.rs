@@ -0,0 +1,149 @@
+use super::Request;
+use crate::hex;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{prelude::*, Result};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/*
+Request bodies streamed to disk.
+
+    let saved = c.request.save_body_to("uploads/image.bin")?;
+    println!("{} bytes, sha256 {}", saved.size, saved.sha256);
+
+The body is written to a temporary file next to the target while its size and
+SHA-256 digest are computed, and renamed to the target once it's complete. A body
+over the limit, a truncated body or a disconnected client leave nothing behind.
+
+`spool_to_tempfile` keeps the body in the upload directory (`RustWeb::upload_dir`,
+the system temp dir by default) until the handler decides what to do with it. The
+file is removed when the `TempFile` is dropped, unless it was `persist`ed.
+*/
+
+static UPLOAD_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// Only once, a second call panics
+pub fn set_upload_dir(dir: &str) {
+    if UPLOAD_DIR.set(PathBuf::from(dir)).is_err() {
+        panic!("upload dir is already set");
+    }
+}
+
+// Created on first use
+pub(crate) fn upload_dir() -> Result<PathBuf> {
+    let dir = UPLOAD_DIR.get().cloned().unwrap_or_else(std::env::temp_dir);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+// A body that was stored completely
+#[derive(Debug, Clone)]
+pub struct SavedBody {
+    pub path: PathBuf,
+    pub size: u64,
+    // Lowercase hex
+    pub sha256: String,
+}
+
+pub struct TempFile {
+    path: PathBuf,
+    size: u64,
+    sha256: String,
+    persisted: bool,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn open(&self) -> Result<File> {
+        File::open(&self.path)
+    }
+
+    // Move the file to `path`, which has to be on the same file system as the
+    // upload directory. On failure the temporary file is removed.
+    pub fn persist<P: AsRef<Path>>(mut self, path: P) -> Result<SavedBody> {
+        fs::rename(&self.path, path.as_ref())?;
+        self.persisted = true;
+        Ok(SavedBody {
+            path: path.as_ref().to_path_buf(),
+            size: self.size,
+            sha256: self.sha256.clone(),
+        })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Request {
+    // Stream the body to `path`, replacing the file only once the whole body arrived
+    pub fn save_body_to<P: AsRef<Path>>(&mut self, path: P) -> Result<SavedBody> {
+        let path = path.as_ref();
+        // Spool next to the target, so the final rename stays on one file system
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        spool(&mut self.body_reader(), &dir, &[])?.persist(path)
+    }
+
+    pub fn spool_to_tempfile(&mut self) -> Result<TempFile> {
+        let dir = upload_dir()?;
+        spool(&mut self.body_reader(), &dir, &[])
+    }
+}
+
+// Write `head` and then everything `reader` gives into a new file in `dir`
+pub(crate) fn spool<R: Read>(reader: &mut R, dir: &Path, head: &[u8]) -> Result<TempFile> {
+    let (mut file, path) = create_temp_file(dir)?;
+    // From here on, dropping the guard removes the file
+    let mut temp = TempFile {
+        path,
+        size: head.len() as u64,
+        sha256: String::new(),
+        persisted: false,
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(head);
+    file.write_all(head)?;
+
+    let mut buf = vec![0; 64 << 10];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+        temp.size += n as u64;
+    }
+    file.sync_all()?;
+
+    temp.sha256 = hex::encode(&hasher.finalize());
+    Ok(temp)
+}
+
+// A new file with a random name, never an existing one
+fn create_temp_file(dir: &Path) -> Result<(File, PathBuf)> {
+    let name = hex::random(8, "temp file names");
+    let path = dir.join(format!(".upload-{}.tmp", name));
+    let file = File::options().write(true).create_new(true).open(&path)?;
+    Ok((file, path))
+}
@@ -1,21 +1,26 @@
-use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::Result;
+use std::io::{self, ErrorKind, Result};
+
+use serde::Serialize;
 
 use self::status::Status;
-use crate::context::Context;
+use crate::context::{Context, Extra};
+use crate::cookie::Cookie;
+use crate::header::HeaderMap;
+use crate::request::limits::LimitError;
 
+pub mod multipart;
 pub mod status;
 
 pub struct Response {
     status: Status,
-    header: HashMap<String, String>,
+    header: HeaderMap,
 
     body: Box<dyn Body>,
 }
 
-pub trait Body {
+pub trait Body: Send {
     fn get_content(&mut self, _buf: &mut Vec<u8>) -> Result<usize> {
         Ok(0)
     }
@@ -107,6 +112,18 @@ impl JsonBody {
             cursor_index: 0,
         }
     }
+
+    pub fn from_value<T: Serialize + ?Sized>(value: &T, pretty: bool) -> serde_json::Result<Self> {
+        let content = if pretty {
+            serde_json::to_vec_pretty(value)?
+        } else {
+            serde_json::to_vec(value)?
+        };
+        Ok(Self {
+            content,
+            cursor_index: 0,
+        })
+    }
 }
 
 impl Body for JsonBody {
@@ -168,7 +185,7 @@ impl Body for FileBody {
 impl dyn Body {}
 
 impl Response {
-    pub fn new(status: Status, header: HashMap<String, String>, body: Box<dyn Body>) -> Self {
+    pub fn new(status: Status, header: HeaderMap, body: Box<dyn Body>) -> Self {
         Self {
             status,
             header,
@@ -179,22 +196,43 @@ impl Response {
         self.status = status
     }
 
-    pub fn set_header(&mut self, header: HashMap<String, String>) {
+    pub fn set_header(&mut self, header: HeaderMap) {
         self.header = header
     }
 
-    pub fn add_header(&mut self, key: &str, value: &str) {
-        self.header.insert(key.to_string(), value.to_string());
+    // Add a header field, keeping earlier ones of the same name (e.g. `Set-Cookie`).
+    // Fails on names or values that would break the header block, like CR/LF.
+    pub fn add_header(&mut self, key: &str, value: &str) -> Result<()> {
+        self.header.append(key, value)
+    }
+
+    pub fn header(&self) -> &HeaderMap {
+        &self.header
+    }
+
+    pub fn header_mut(&mut self) -> &mut HeaderMap {
+        &mut self.header
+    }
+
+    // One `Set-Cookie` header per cookie
+    pub fn add_cookie(&mut self, cookie: &Cookie) -> Result<()> {
+        cookie.validate()?;
+        self.header.append("Set-Cookie", &cookie.to_string())
     }
 
     pub fn set_body(&mut self, body: Box<dyn Body>) {
         self.body = body
     }
 
+    #[cfg(feature = "event-loop")]
+    pub(crate) fn has_body(&self) -> bool {
+        self.body.get_content_length().unwrap_or(0) > 0
+    }
+
     pub fn build() -> Response {
         Response {
             status: Status::OK,
-            header: HashMap::new(),
+            header: HeaderMap::new(),
             body: Box::new(NoneContent {}),
         }
     }
@@ -202,7 +240,7 @@ impl Response {
     pub fn build_error(status: Status) -> Response {
         Response {
             status,
-            header: HashMap::new(),
+            header: HeaderMap::new(),
             body: Box::new(NoneContent {}),
         }
     }
@@ -215,12 +253,19 @@ impl Response {
 
     pub fn get_header(&mut self) -> Vec<u8> {
         let mut headers = String::new();
-        for (key, value) in &self.header {
+        for (key, value) in self.header.iter() {
             headers.push_str(&format!("{key}: {value}"));
             headers.push_str("\r\n");
         }
         let content_type = self.body.get_content_type();
-        if !content_type.is_empty() {
+        if content_type.is_empty() {
+            // Without a body the response still needs an end, unless it never has one
+            let code = self.status.code();
+            let bodiless = code < 200 || code == 204 || code == 304;
+            if !bodiless && !self.header.contains_key("Content-Length") {
+                headers.push_str("Content-Length: 0\r\n");
+            }
+        } else {
             let content_length = self.body.get_content_length().unwrap_or(0);
             let content_disposition = self.body.get_content_disposition();
             headers.push_str(&format!("Content-Type: {}\r\n", content_type));
@@ -237,37 +282,41 @@ impl Response {
 
 impl Response {
     pub fn response(&mut self, context: &mut Context) -> Result<()> {
-        let stream = &mut context.stream;
-        stream.write(&self.get_status_line())?;
-        stream.write(&self.get_header())?;
+        let extra = std::mem::take(&mut *context.extra.lock().unwrap());
+        self.prepare(extra)?;
+        self.write_to(&mut context.stream)?;
+        #[cfg(feature = "event-loop")]
+        crate::event_loop::reuse(context, self);
+        Ok(())
+    }
+
+    // Save the session and add the header fields the handler set on its context,
+    // validated when they were added
+    pub(crate) fn prepare(&mut self, extra: Extra) -> Result<()> {
+        let Extra {
+            mut header,
+            session,
+        } = extra;
+        crate::session::commit(session, &mut header)?;
+        for (key, value) in header.iter() {
+            self.header.append(key, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, stream: &mut impl Write) -> Result<()> {
+        stream.write_all(&self.get_status_line())?;
+        stream.write_all(&self.get_header())?;
 
         // body
-        let body = &mut self.body;
-        let content_length = body.get_content_length().unwrap_or(0);
+        let content_length = self.body.get_content_length().unwrap_or(0);
         if content_length > 0 {
-            let mut buf = if content_length < 8 * 1024 {
-                vec![0u8; 8 * 1024]
-            } else {
-                vec![0u8; content_length]
-            };
+            // Large bodies are sent in pieces, not buffered whole
+            let mut buf = piece_buffer(content_length);
             let mut writed = 0usize;
-            loop {
-                match body.get_content(&mut buf) {
-                    Ok(n) => {
-                        if n <= 0 {
-                            break;
-                        }
-                        stream.write(&buf[..n])?;
-                        writed += n;
-                        if writed >= content_length {
-                            break;
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!("Error occured when response: {}", err);
-                        break;
-                    }
-                }
+            while let Some(n) = self.next_piece(&mut buf, writed, content_length) {
+                stream.write_all(&buf[..n])?;
+                writed += n;
             }
         }
 
@@ -275,4 +324,146 @@ impl Response {
 
         Ok(())
     }
+
+    // The same for async handlers, written to the socket registered with the runtime
+    #[cfg(feature = "async")]
+    pub async fn write_to_async<W>(&mut self, stream: &mut W) -> Result<()>
+    where
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        stream.write_all(&self.get_status_line()).await?;
+        stream.write_all(&self.get_header()).await?;
+
+        let content_length = self.body.get_content_length().unwrap_or(0);
+        if content_length > 0 {
+            let mut buf = piece_buffer(content_length);
+            let mut writed = 0usize;
+            while let Some(n) = self.next_piece(&mut buf, writed, content_length) {
+                stream.write_all(&buf[..n]).await?;
+                writed += n;
+            }
+        }
+
+        stream.flush().await?;
+
+        Ok(())
+    }
+
+    // The next piece of the body in `buf`, None once `content_length` bytes are
+    // written or the body ends early
+    fn next_piece(
+        &mut self,
+        buf: &mut Vec<u8>,
+        writed: usize,
+        content_length: usize,
+    ) -> Option<usize> {
+        if writed >= content_length {
+            return None;
+        }
+        match self.body.get_content(buf) {
+            Ok(0) => None,
+            Ok(n) => Some(n),
+            Err(err) => {
+                eprintln!("Error occured when response: {}", err);
+                None
+            }
+        }
+    }
+}
+
+fn piece_buffer(content_length: usize) -> Vec<u8> {
+    vec![0u8; content_length.clamp(8 * 1024, 64 * 1024)]
+}
+
+// Conversion of a handler's return value into the response sent to the client
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for Status {
+    fn into_response(self) -> Response {
+        Response::build_error(self)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        let body = TextBody::new("text/plain; charset=utf-8".to_string(), self.into_bytes());
+        Response::new(Status::OK, HeaderMap::new(), Box::new(body))
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        self.to_string().into_response()
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        let body = TextBody::new("application/octet-stream".to_string(), self);
+        Response::new(Status::OK, HeaderMap::new(), Box::new(body))
+    }
+}
+
+impl IntoResponse for JsonBody {
+    fn into_response(self) -> Response {
+        Response::new(Status::OK, HeaderMap::new(), Box::new(self))
+    }
+}
+
+// Errors from reading the request: a `LimitError` answers with its own status,
+// malformed or truncated input with 400 and anything else with 500
+impl IntoResponse for io::Error {
+    fn into_response(self) -> Response {
+        if let Some(err) = LimitError::of(&self) {
+            return err.clone().into_response();
+        }
+        let status = match self.kind() {
+            ErrorKind::InvalidData | ErrorKind::InvalidInput | ErrorKind::UnexpectedEof => {
+                Status::BadRequest
+            }
+            _ => Status::InternalServerError,
+        };
+        error_response(status, &self.to_string(), serde_json::Value::Null)
+    }
+}
+
+// The JSON answer to an error, {"code":...,"message":...} and the fields of `extra`
+// if it's an object
+pub(crate) fn error_response(status: Status, message: &str, extra: serde_json::Value) -> Response {
+    let mut content = serde_json::json!({
+        "code": status.code(),
+        "message": message,
+    });
+    if let (Some(content), serde_json::Value::Object(extra)) = (content.as_object_mut(), extra) {
+        content.extend(extra);
+    }
+    let body = JsonBody::new(content.to_string().as_bytes());
+    Response::new(status, HeaderMap::new(), Box::new(body))
+}
+
+impl<T: IntoResponse> IntoResponse for (Status, T) {
+    fn into_response(self) -> Response {
+        let mut response = self.1.into_response();
+        response.set_status(self.0);
+        response
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for std::result::Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
 }
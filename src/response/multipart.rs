@@ -0,0 +1,377 @@
+use super::{Body, IntoResponse, Response};
+use crate::header::HeaderMap;
+use crate::hex;
+use crate::response::status::Status;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result, SeekFrom};
+use std::ops::Range;
+
+/*
+A multipart response body, parts are written one after another as they're sent.
+
+    let body = MultipartBody::mixed()
+        .part(header, b"{\"id\":1}".to_vec())
+        .file_part(header, file, 0, size);
+
+Each part has its own header and either bytes or a range of a file as content.
+The boundary is random, the length is known up front so Content-Length is set.
+
+`byteranges` builds the body of a 206 answer to a request for several ranges:
+
+    let body = MultipartBody::byteranges(file, "video/mp4", &[0..500, 1000..1500])?;
+    c.respond((Status::PartialContent, body));
+*/
+pub struct MultipartBody {
+    // "multipart/mixed; boundary=..."
+    content_type: String,
+    boundary: String,
+    parts: Vec<Part>,
+    // Framing (boundaries and part headers) not sent yet
+    pending: Vec<u8>,
+    pending_pos: usize,
+    // The part whose content is being sent and how much of it was
+    current: Option<(usize, u64)>,
+    // Index of the next part to start
+    next: usize,
+    closed: bool,
+}
+
+struct Part {
+    header: HeaderMap,
+    content: Content,
+}
+
+enum Content {
+    Bytes(Vec<u8>),
+    File { file: File, offset: u64, len: u64 },
+}
+
+impl Content {
+    fn len(&self) -> u64 {
+        match self {
+            Content::Bytes(data) => data.len() as u64,
+            Content::File { len, .. } => *len,
+        }
+    }
+}
+
+impl MultipartBody {
+    // "multipart/<subtype>", e.g. "related" or "form-data"
+    pub fn new(subtype: &str) -> Self {
+        // 32 hex digits, not going to show up in any part
+        let boundary = hex::random(16, "multipart boundaries");
+        Self {
+            content_type: format!("multipart/{}; boundary={}", subtype, boundary),
+            boundary,
+            parts: Vec::new(),
+            pending: Vec::new(),
+            pending_pos: 0,
+            current: None,
+            next: 0,
+            closed: false,
+        }
+    }
+
+    pub fn mixed() -> Self {
+        Self::new("mixed")
+    }
+
+    // One part per range of `file` with its Content-Range, `content_type` is the
+    // type of the file. Ranges are start..end in bytes and have to lie in the file.
+    pub fn byteranges(file: File, content_type: &str, ranges: &[Range<u64>]) -> Result<Self> {
+        let size = file.metadata()?.len();
+        let mut body = Self::new("byteranges");
+        for range in ranges {
+            if range.start >= range.end || range.end > size {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("range {:?} not in file of {} bytes", range, size),
+                ));
+            }
+            let mut header = HeaderMap::new();
+            header.append("Content-Type", content_type)?;
+            header.append(
+                "Content-Range",
+                &format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+            )?;
+            // The clones share one cursor. That works because parts are sent one
+            // after another and each seeks to its offset before its first read.
+            let file = file.try_clone()?;
+            body = body.file_part(header, file, range.start, range.end - range.start);
+        }
+        Ok(body)
+    }
+
+    pub fn boundary(&self) -> &str {
+        &self.boundary
+    }
+
+    pub fn part(mut self, header: HeaderMap, content: Vec<u8>) -> Self {
+        self.parts.push(Part {
+            header,
+            content: Content::Bytes(content),
+        });
+        self
+    }
+
+    // `len` bytes of `file` from `offset` on
+    pub fn file_part(mut self, header: HeaderMap, file: File, offset: u64, len: u64) -> Self {
+        self.parts.push(Part {
+            header,
+            content: Content::File { file, offset, len },
+        });
+        self
+    }
+
+    // "--boundary" line and header of a part, with the line break ending the
+    // previous part's content in front
+    fn part_head(&self, index: usize) -> Vec<u8> {
+        let mut head = String::new();
+        if index > 0 {
+            head.push_str("\r\n");
+        }
+        head.push_str(&format!("--{}\r\n", self.boundary));
+        for (key, value) in self.parts[index].header.iter() {
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    fn close_delimiter(&self) -> Vec<u8> {
+        let nl = if self.parts.is_empty() { "" } else { "\r\n" };
+        format!("{}--{}--\r\n", nl, self.boundary).into_bytes()
+    }
+
+    // Content of the current part into `buf`, 0 once it's all sent
+    fn read_content(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let (index, sent) = match self.current {
+            Some(current) => current,
+            None => return Ok(0),
+        };
+        let left = self.parts[index].content.len() - sent;
+        let max = buf.len().min(left.min(usize::MAX as u64) as usize);
+
+        let n = match &mut self.parts[index].content {
+            Content::Bytes(data) => {
+                let start = sent as usize;
+                buf[..max].copy_from_slice(&data[start..start + max]);
+                max
+            }
+            Content::File { file, offset, .. } => {
+                if sent == 0 {
+                    file.seek(SeekFrom::Start(*offset))?;
+                }
+                let n = file.read(&mut buf[..max])?;
+                if n == 0 && max > 0 {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "file shorter than its multipart range",
+                    ));
+                }
+                n
+            }
+        };
+
+        if n == 0 {
+            self.current = None;
+        } else {
+            self.current = Some((index, sent + n as u64));
+        }
+        Ok(n)
+    }
+}
+
+impl Body for MultipartBody {
+    fn get_content(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        loop {
+            if self.pending_pos < self.pending.len() {
+                let n = buf.len().min(self.pending.len() - self.pending_pos);
+                buf[..n].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + n]);
+                self.pending_pos += n;
+                return Ok(n);
+            }
+
+            let n = self.read_content(buf)?;
+            if n > 0 {
+                return Ok(n);
+            }
+
+            self.pending = if self.next < self.parts.len() {
+                self.current = Some((self.next, 0));
+                self.next += 1;
+                self.part_head(self.next - 1)
+            } else if !self.closed {
+                self.closed = true;
+                self.close_delimiter()
+            } else {
+                return Ok(0);
+            };
+            self.pending_pos = 0;
+        }
+    }
+
+    fn get_content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn get_content_length(&self) -> Result<usize> {
+        let mut length = self.close_delimiter().len() as u64;
+        for (index, part) in self.parts.iter().enumerate() {
+            length += self.part_head(index).len() as u64 + part.content.len();
+        }
+        Ok(length as usize)
+    }
+}
+
+impl IntoResponse for MultipartBody {
+    fn into_response(self) -> Response {
+        Response::new(Status::OK, HeaderMap::new(), Box::new(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::from_raw;
+
+    // Everything the body sends, in pieces of at most `size` bytes
+    fn content(body: &mut MultipartBody, size: usize) -> Vec<u8> {
+        let mut content = Vec::new();
+        let mut buf = vec![0; size];
+        loop {
+            match body.get_content(&mut buf).unwrap() {
+                0 => return content,
+                n => content.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn header(fields: &[(&str, &str)]) -> HeaderMap {
+        let mut header = HeaderMap::new();
+        for (name, value) in fields {
+            header.append(name, value).unwrap();
+        }
+        header
+    }
+
+    fn temp_file(name: &str, data: &[u8]) -> (File, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("rustweb-{}-{}", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        (File::open(&path).unwrap(), path)
+    }
+
+    fn json_and_empty() -> MultipartBody {
+        MultipartBody::mixed()
+            .part(
+                header(&[("Content-Type", "application/json")]),
+                b"{\"id\":1}".to_vec(),
+            )
+            .part(HeaderMap::new(), Vec::new())
+    }
+
+    #[test]
+    fn boundary_and_framing() {
+        let body = json_and_empty();
+        let boundary = body.boundary();
+        assert_eq!(boundary.len(), 32);
+        assert!(boundary.bytes().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(boundary, json_and_empty().boundary());
+        assert_eq!(
+            body.get_content_type(),
+            format!("multipart/mixed; boundary={}", boundary)
+        );
+
+        for size in [1, 7, 4096] {
+            let mut body = json_and_empty();
+            let expected = format!(
+                "--{b}\r\nContent-Type: application/json\r\n\r\n{{\"id\":1}}\r\n\
+                 --{b}\r\n\r\n\r\n--{b}--\r\n",
+                b = body.boundary()
+            );
+            assert_eq!(body.get_content_length().unwrap(), expected.len());
+            assert_eq!(
+                String::from_utf8(content(&mut body, size)).unwrap(),
+                expected
+            );
+        }
+
+        let mut empty = MultipartBody::new("related");
+        let close = format!("--{}--\r\n", empty.boundary());
+        assert!(empty.get_content_type().starts_with("multipart/related; "));
+        assert_eq!(empty.get_content_length().unwrap(), close.len());
+        assert_eq!(content(&mut empty, 5), close.as_bytes());
+    }
+
+    #[test]
+    fn byteranges() {
+        let data: Vec<u8> = (0..=255).collect();
+        let (file, path) = temp_file("byteranges", &data);
+        let mut body =
+            MultipartBody::byteranges(file, "video/mp4", &[10..20, 0..3, 250..256]).unwrap();
+        assert!(body
+            .get_content_type()
+            .starts_with("multipart/byteranges; boundary="));
+        let length = body.get_content_length().unwrap();
+        let encoded = content(&mut body, 3);
+        assert_eq!(encoded.len(), length);
+
+        // Read back with the request parser
+        let mut request = from_raw(
+            &[
+                format!(
+                    "POST / HTTP/1.1\r\nContent-Type: multipart/form-data; boundary={}\r\n\
+                     Content-Length: {}\r\n\r\n",
+                    body.boundary(),
+                    length
+                )
+                .into_bytes(),
+                encoded,
+            ]
+            .concat(),
+        );
+        let mut parts = Vec::new();
+        for part in request.multipart() {
+            let mut part = part.unwrap();
+            let mut content = Vec::new();
+            part.read_to_end(&mut content).unwrap();
+            let range = part.header.get("Content-Range").unwrap().to_string();
+            assert_eq!(part.header.get("Content-Type"), Some("video/mp4"));
+            parts.push((range, content));
+        }
+        assert_eq!(
+            parts,
+            [
+                ("bytes 10-19/256".to_string(), data[10..20].to_vec()),
+                ("bytes 0-2/256".to_string(), data[0..3].to_vec()),
+                ("bytes 250-255/256".to_string(), data[250..].to_vec()),
+            ]
+        );
+
+        for (start, end) in [(5, 5), (6, 2), (250, 257)] {
+            let file = File::open(&path).unwrap();
+            let err = MultipartBody::byteranges(file, "video/mp4", &[0..1, start..end])
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_shorter_than_its_range() {
+        let (file, path) = temp_file("short", b"abc");
+        let mut body = MultipartBody::mixed().file_part(HeaderMap::new(), file, 1, 5);
+        let mut buf = vec![0; 64];
+        let err = loop {
+            match body.get_content(&mut buf) {
+                Ok(0) => panic!("no error"),
+                Ok(_) => (),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        std::fs::remove_file(path).unwrap();
+    }
+}
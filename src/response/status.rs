@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
     // 1xx informational response
     Continue = 100,
@@ -73,6 +74,10 @@ pub enum Status {
 }
 
 impl Status {
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+
     pub fn to_string(&self) -> &'static str {
         match &self {
             Status::Continue => "100 Continue",
@@ -1,5 +1,11 @@
 use crate::context::Context;
+use crate::request::limits::MultipartLimits;
+use crate::request::query::QueryMap;
+#[cfg(feature = "async")]
+use crate::response::Response;
 use std::collections::HashMap;
+#[cfg(feature = "async")]
+use std::{future::Future, pin::Pin};
 
 /*
 Here, we use static Option<HashMap<..>> instead of static HashMap<..>
@@ -12,9 +18,22 @@ static mut ROUTER: Option<HashMap<String, RoutingItem>> = None;
 pub struct RoutingItem {
     pub path: String,
     pub method: String,
-    pub func: fn(Context),
+    pub handler: Handler,
+    // Overrides the global body limit
+    pub body_limit: Option<usize>,
+    pub multipart_limits: Option<MultipartLimits>,
 }
 
+pub enum Handler {
+    Sync(fn(Context)),
+    #[cfg(feature = "async")]
+    Async(AsyncHandler),
+}
+
+#[cfg(feature = "async")]
+pub type AsyncHandler =
+    Box<dyn Fn(Context) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
+
 pub fn insert(key: &str, value: RoutingItem) {
     unsafe {
         if let None = ROUTER {
@@ -24,7 +43,31 @@ pub fn insert(key: &str, value: RoutingItem) {
     }
 }
 
-pub fn find(key: &str) -> Option<&'static RoutingItem> {
+pub fn set_body_limit(key: &str, limit: usize) {
+    unsafe {
+        let router = &mut *std::ptr::addr_of_mut!(ROUTER);
+        if let Some(item) = router.as_mut().and_then(|router| router.get_mut(key)) {
+            item.body_limit = Some(limit);
+        } else {
+            println!("body limit for unknown route: {}", key);
+        }
+    }
+}
+
+pub fn set_multipart_limits(key: &str, limits: MultipartLimits) {
+    unsafe {
+        let router = &mut *std::ptr::addr_of_mut!(ROUTER);
+        if let Some(item) = router.as_mut().and_then(|router| router.get_mut(key)) {
+            item.multipart_limits = Some(limits);
+        } else {
+            println!("multipart limits for unknown route: {}", key);
+        }
+    }
+}
+
+// Exact paths win, otherwise the matching pattern like "/user/:id" whose
+// literal segments come first, see `precedence`. Returns the parameter values too.
+pub fn find(key: &str) -> Option<(&'static RoutingItem, QueryMap)> {
     unsafe {
         if let None = ROUTER {
             ROUTER = Some(HashMap::new());
@@ -35,6 +78,81 @@ pub fn find(key: &str) -> Option<&'static RoutingItem> {
         } else {
             key
         };
-        ROUTER.as_ref().unwrap().get(new_key)
+        let router = ROUTER.as_ref().unwrap();
+        if let Some(item) = router.get(new_key) {
+            return Some((item, QueryMap::new()));
+        }
+
+        router
+            .values()
+            .filter(|item| item.path.contains("/:"))
+            .filter_map(|item| match_pattern(&item.path, new_key).map(|params| (item, params)))
+            .min_by_key(|(item, _)| precedence(&item.path))
+    }
+}
+
+// Segments are compared from the left and a literal beats a parameter, so for
+// "/user/about" "/user/:id" wins over "/:lang/about". Ties go by pattern text
+// so the choice never depends on the HashMap order.
+fn precedence(pattern: &str) -> (Vec<bool>, &str) {
+    let params = pattern.split('/').map(|s| s.starts_with(':')).collect();
+    (params, pattern)
+}
+
+fn match_pattern(pattern: &str, path: &str) -> Option<QueryMap> {
+    let patterns: Vec<&str> = pattern.split('/').collect();
+    let segments: Vec<&str> = path.split('/').collect();
+    if patterns.len() != segments.len() {
+        return None;
+    }
+
+    let mut params = QueryMap::new();
+    for (p, s) in patterns.iter().zip(segments.iter()) {
+        match p.strip_prefix(':') {
+            Some(name) if !s.is_empty() => params.append(name.to_string(), s.to_string()),
+            Some(_) => return None,
+            None if p == s => (),
+            None => return None,
+        }
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str) {
+        insert(
+            path,
+            RoutingItem {
+                path: path.to_string(),
+                method: "GET".to_string(),
+                handler: Handler::Sync(|_| ()),
+                body_limit: None,
+                multipart_limits: None,
+            },
+        );
+    }
+
+    #[test]
+    fn literal_segments_beat_params() {
+        route("/:lang/about");
+        route("/user/:id");
+        route("/:a/:b");
+        route("/user/me");
+
+        let (item, params) = find("/user/about").unwrap();
+        assert_eq!(item.path, "/user/:id");
+        assert_eq!(params.get("id"), Some("about"));
+
+        let (item, params) = find("/en/about?x=1").unwrap();
+        assert_eq!(item.path, "/:lang/about");
+        assert_eq!(params.get("lang"), Some("en"));
+
+        assert_eq!(find("/x/y").unwrap().0.path, "/:a/:b");
+        assert_eq!(find("/user/me").unwrap().0.path, "/user/me");
+        assert!(find("/user/").is_none());
+        assert!(find("/a/b/c").is_none());
     }
 }
@@ -0,0 +1,119 @@
+use crate::context::Context;
+use crate::response::IntoResponse;
+use crate::router::{AsyncHandler, Handler};
+use std::future::Future;
+use std::io::Result;
+use std::sync::{Arc, OnceLock};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+
+/*
+Async handlers don't run on the pool. The pool worker reads the request head as
+usual, registers the connection with a shared multi-threaded runtime and hands it
+the handler's future, then goes back to serving other connections. The body is
+read from the runtime (see `Request::body_async`) and when the future resolves,
+its output is written there too, along with the session and the header fields the
+handler set on its context. Neither holds a runtime thread while the client is
+slow.
+
+The socket is non-blocking from then on, `Context.stream` included, so async
+handlers return their response rather than writing it themselves.
+*/
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+pub(crate) fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("rustweb-async")
+            .build()
+            .unwrap()
+    })
+}
+
+pub fn handler<F, Fut, R>(handle_func: F) -> Handler
+where
+    F: Fn(Context) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = R> + Send + 'static,
+    R: IntoResponse,
+{
+    Handler::Async(Box::new(move |context| {
+        let future = handle_func(context);
+        Box::pin(async move { future.await.into_response() })
+    }))
+}
+
+pub fn spawn(handler: &AsyncHandler, mut context: Context) {
+    let mut stream = match into_async(&mut context) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("error occurred at runtime::spawn: {}", err);
+            return;
+        }
+    };
+
+    // The handler owns the context, what it adds for the response is taken back
+    // from here once its future resolved
+    let extra = Arc::clone(&context.extra);
+
+    let future = handler(context);
+    runtime().spawn(async move {
+        let mut response = future.await;
+        let extra = std::mem::take(&mut *extra.lock().unwrap());
+        let result = match response.prepare(extra) {
+            Ok(()) => response.write_to_async(&mut stream).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            println!("error occurred at runtime::spawn: {}", err);
+        }
+    });
+}
+
+// Register the connection with the runtime, the request reads the rest of its
+// body from the read half and the response goes to the write half
+pub(crate) fn into_async(context: &mut Context) -> Result<OwnedWriteHalf> {
+    let _guard = runtime().enter();
+    let stream = context.stream.try_clone()?;
+    stream.set_nonblocking(true)?;
+    let (read, write) = TcpStream::from_std(stream)?.into_split();
+    context.request.start_async(read);
+    Ok(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Connection;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn echoes_body_from_runtime() {
+        let echo = match handler(|mut c: Context| async move { c.request.body_async().await }) {
+            Handler::Async(f) => f,
+            _ => unreachable!(),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        client.write_all(b" world").unwrap();
+        let context = Context::new(Connection {
+            address,
+            stream,
+            buffered: b"POST /echo HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello".to_vec(),
+            #[cfg(feature = "event-loop")]
+            keep_alive: None,
+        })
+        .unwrap();
+        spawn(&echo, context);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 11\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello world"));
+    }
+}
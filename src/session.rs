@@ -0,0 +1,329 @@
+use crate::cookie::{Cookie, SameSite};
+use crate::header::HeaderMap;
+use crate::hex;
+use crate::request::Request;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/*
+Server-side sessions.
+
+The client only holds a random id in the `rustweb_session` cookie, the data lives in
+a `SessionStore`. A handler gets the session of its request with `c.session()`, it
+is loaded on first use and saved when the response is sent. A cookie is only issued
+once something is stored in a new session.
+
+    web.sessions(MemoryStore::new(Duration::from_secs(30 * 60)));
+
+    fn login_handler(mut c: Context) {
+        let session = c.session();
+        session.rotate(); // new id after a privilege change
+        session.insert("user", "sfdex");
+        ...
+    }
+*/
+
+pub const SESSION_COOKIE: &str = "rustweb_session";
+// Session ids are this many random bytes as hex
+const ID_BYTES: usize = 32;
+
+pub type SessionData = HashMap<String, String>;
+
+// Where session data is kept. Stores expire sessions that were idle for too long
+// by themselves, `load` returns None for those.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Option<SessionData>;
+    fn save(&self, id: &str, data: &SessionData) -> Result<()>;
+    fn destroy(&self, id: &str);
+}
+
+static STORE: OnceLock<Box<dyn SessionStore>> = OnceLock::new();
+
+// Used when sessions are used without configuring a store
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// Only once, a second call panics
+pub fn set_store(store: Box<dyn SessionStore>) {
+    if STORE.set(store).is_err() {
+        panic!("session store is already set");
+    }
+}
+
+fn store() -> &'static dyn SessionStore {
+    STORE
+        .get_or_init(|| Box::new(MemoryStore::new(DEFAULT_IDLE_TIMEOUT)))
+        .as_ref()
+}
+
+// The session of a request. A handle, clones refer to the same session, so it can
+// be kept while the context is used for something else.
+#[derive(Clone)]
+pub struct Session {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    id: String,
+    data: SessionData,
+    is_new: bool,
+    dirty: bool,
+    // Ids given up by `rotate`, removed from the store on save
+    rotated: Vec<String>,
+    destroyed: bool,
+}
+
+impl Session {
+    pub fn id(&self) -> String {
+        self.state().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state().data.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, value: &str) {
+        let mut state = self.state();
+        state.data.insert(key.to_string(), value.to_string());
+        state.dirty = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.state();
+        state.dirty = true;
+        state.data.remove(key)
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.data.clear();
+        state.dirty = true;
+    }
+
+    // Move the data to a new id, e.g. on login, so an id known to someone else
+    // before (session fixation) becomes useless
+    pub fn rotate(&self) {
+        let mut state = self.state();
+        if !state.is_new {
+            let id = state.id.clone();
+            state.rotated.push(id);
+        }
+        state.id = new_id();
+        state.is_new = true;
+        state.dirty = true;
+    }
+
+    // Remove the session from the store and the client, e.g. on logout
+    pub fn destroy(&self) {
+        let mut state = self.state();
+        state.data.clear();
+        state.destroyed = true;
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn new(id: String, data: SessionData, is_new: bool) -> Self {
+        let state = State {
+            id,
+            data,
+            is_new,
+            dirty: false,
+            rotated: vec![],
+            destroyed: false,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn fresh() -> Self {
+        Self::new(new_id(), SessionData::new(), true)
+    }
+}
+
+// The session of the request, a new one if it has none or it expired
+pub fn load(request: &Request) -> Session {
+    let id = request.cookie(SESSION_COOKIE);
+    if !hex::is_random(&id, ID_BYTES) {
+        return Session::fresh();
+    }
+
+    match store().load(&id) {
+        Some(data) => Session::new(id, data, false),
+        None => Session::fresh(),
+    }
+}
+
+// Persist the session, if it was used, and set the cookie in `header`
+pub fn commit(session: Option<Session>, header: &mut HeaderMap) -> Result<()> {
+    let session = match &session {
+        Some(session) => session.state(),
+        None => return Ok(()),
+    };
+
+    let store = store();
+    for id in &session.rotated {
+        store.destroy(id);
+    }
+
+    if session.destroyed {
+        if !session.is_new {
+            store.destroy(&session.id);
+        }
+        let removal = Cookie::removal(SESSION_COOKIE).path("/");
+        return header.append("Set-Cookie", &removal.to_string());
+    }
+
+    // Nothing worth a cookie yet
+    if session.is_new && session.data.is_empty() {
+        return Ok(());
+    }
+
+    if session.dirty {
+        store.save(&session.id, &session.data)?;
+    }
+
+    if session.is_new {
+        let cookie = Cookie::new(SESSION_COOKIE, &session.id)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        header.append("Set-Cookie", &cookie.to_string())?;
+    }
+    Ok(())
+}
+
+fn new_id() -> String {
+    hex::random(ID_BYTES, "session ids")
+}
+
+// Sessions in memory, lost on restart
+pub struct MemoryStore {
+    idle_timeout: Duration,
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+impl MemoryStore {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(id) {
+            Some((data, last_access)) if last_access.elapsed() < self.idle_timeout => {
+                *last_access = Instant::now();
+                Some(data.clone())
+            }
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let idle_timeout = self.idle_timeout;
+        sessions.retain(|_, (_, last_access)| last_access.elapsed() < idle_timeout);
+        sessions.insert(id.to_string(), (data.clone(), Instant::now()));
+        Ok(())
+    }
+
+    fn destroy(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+// Sessions as JSON files in a directory, one per session. The modification time
+// is the last access.
+pub struct FileStore {
+    dir: PathBuf,
+    idle_timeout: Duration,
+    last_cleanup: Mutex<Instant>,
+}
+
+impl FileStore {
+    pub fn new(dir: &str, idle_timeout: Duration) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            idle_timeout,
+            last_cleanup: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn is_expired(&self, path: &PathBuf) -> bool {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+        match modified.map(|modified| modified.elapsed()) {
+            Ok(Ok(idle)) => idle >= self.idle_timeout,
+            // Modified in the future, the clock went back
+            Ok(Err(_)) => false,
+            Err(_) => true,
+        }
+    }
+
+    // Remove expired sessions, at most once a minute
+    fn cleanup(&self) {
+        let mut last_cleanup = self.last_cleanup.lock().unwrap();
+        if last_cleanup.elapsed() < Duration::from_secs(60) {
+            return;
+        }
+        *last_cleanup = Instant::now();
+
+        if let Ok(entries) = fs::read_dir(&self.dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "json") && self.is_expired(&path) {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let path = self.path(id);
+        if self.is_expired(&path) {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+
+        let content = fs::read(&path).ok()?;
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        serde_json::from_slice(&content).ok()
+    }
+
+    fn save(&self, id: &str, data: &SessionData) -> Result<()> {
+        self.cleanup();
+
+        // Write aside and rename, so a concurrent load never sees half a file
+        let content =
+            serde_json::to_vec(data).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let tmp = self.dir.join(format!("{}.tmp", id));
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, self.path(id))
+    }
+
+    fn destroy(&self, id: &str) {
+        let _ = fs::remove_file(self.path(id));
+    }
+}
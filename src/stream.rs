@@ -0,0 +1,103 @@
+use std::io::{Read, Result};
+use std::net::TcpStream;
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, ReadBuf};
+#[cfg(feature = "async")]
+use tokio::net::tcp::OwnedReadHalf;
+
+// Stream is the read half of a connection. Bytes that were already taken off the
+// socket before the connection reached a worker (by the event loop) are replayed
+// first, then reading continues from the socket itself.
+pub struct Stream {
+    pending: Pending,
+    inner: TcpStream,
+}
+
+impl Stream {
+    pub fn new(pending: Vec<u8>, inner: TcpStream) -> Self {
+        Self {
+            pending: Pending::new(pending),
+            inner,
+        }
+    }
+
+    // Replayed bytes not read yet
+    pub fn pending(&self) -> &[u8] {
+        self.pending.rest()
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.pending.read(buf) {
+            0 => self.inner.read(buf),
+            n => Ok(n),
+        }
+    }
+}
+
+// The same for async handlers, over the read half of the socket registered with
+// the runtime
+#[cfg(feature = "async")]
+pub struct AsyncStream {
+    pending: Pending,
+    inner: OwnedReadHalf,
+}
+
+#[cfg(feature = "async")]
+impl AsyncStream {
+    pub fn new(pending: Vec<u8>, inner: OwnedReadHalf) -> Self {
+        Self {
+            pending: Pending::new(pending),
+            inner,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let n = self.pending.read(buf.initialize_unfilled());
+        if n > 0 {
+            buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+struct Pending {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Pending {
+    fn new(data: Vec<u8>) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn rest(&self) -> &[u8] {
+        &self.data[self.pos..]
+    }
+
+    // 0 once everything was replayed
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        if self.pos == self.data.len() {
+            self.data = Vec::new();
+            self.pos = 0;
+        }
+        n
+    }
+}
@@ -1,11 +1,40 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+/*
+The pool starts `min` workers up front. When a job is submitted and more jobs are
+queued than there are workers waiting for one (or about to), a new worker is
+spawned as long as there are fewer than `max`. A worker that
+stays idle for `keep_alive` shuts itself down, until only `min` workers are left.
+A job that panics is reported and the worker goes on with the next one.
+*/
 pub struct ThreadPool {
-    workers: Vec<Worker>,
+    workers: Mutex<Vec<Worker>>,
     sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    receiver: Mutex<mpsc::Receiver<Job>>,
+    min: usize,
+    max: usize,
+    keep_alive: Duration,
+    size: AtomicUsize,
+    // Workers waiting for a job
+    idle: AtomicUsize,
+    // Workers spawned that didn't start waiting yet
+    starting: AtomicUsize,
+    // Jobs sent that no worker took yet
+    queued: AtomicUsize,
+    next_id: AtomicUsize,
 }
 
 struct Worker {
@@ -16,20 +45,33 @@ struct Worker {
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+    pub fn new(min: usize, max: usize, keep_alive: Duration) -> ThreadPool {
+        assert!(min > 0);
+        assert!(max >= min);
 
         let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            min,
+            max,
+            keep_alive,
+            size: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            starting: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            next_id: AtomicUsize::new(0),
+        });
 
-        let mut workers = Vec::with_capacity(size);
+        let mut workers = Vec::with_capacity(max);
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        for _ in 0..min {
+            shared.size.fetch_add(1, Ordering::SeqCst);
+            workers.push(Worker::new(Arc::clone(&shared)));
         }
         ThreadPool {
-            workers,
+            workers: Mutex::new(workers),
             sender: Some(sender),
+            shared,
         }
     }
 
@@ -39,7 +81,37 @@ impl ThreadPool {
     {
         let job = Box::new(f);
 
+        let shared = &self.shared;
+        shared.queued.fetch_add(1, Ordering::SeqCst);
         self.sender.as_ref().unwrap().send(job).unwrap();
+
+        let waiting = shared.idle.load(Ordering::SeqCst) + shared.starting.load(Ordering::SeqCst);
+        if shared.queued.load(Ordering::SeqCst) > waiting {
+            self.grow();
+        }
+    }
+
+    // Spawn one more worker unless the pool is already at its maximum size
+    fn grow(&self) {
+        let reserved = self
+            .shared
+            .size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                (size < self.shared.max).then_some(size + 1)
+            });
+        if reserved.is_err() {
+            return;
+        }
+
+        let mut workers = self.workers.lock().unwrap();
+        // Forget about workers that already shut down after being idle
+        workers.retain(|worker| {
+            worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| !thread.is_finished())
+        });
+        workers.push(Worker::new(Arc::clone(&self.shared)));
     }
 }
 
@@ -47,7 +119,7 @@ impl Drop for ThreadPool {
     fn drop(&mut self) {
         drop(self.sender.take());
 
-        for worker in &mut self.workers {
+        for worker in self.workers.get_mut().unwrap() {
             println!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
@@ -58,16 +130,52 @@ impl Drop for ThreadPool {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(shared: Arc<Shared>) -> Worker {
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        shared.starting.fetch_add(1, Ordering::SeqCst);
+
+        let mut starting = true;
+        let mut idle_since = Instant::now();
         let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+            shared.idle.fetch_add(1, Ordering::SeqCst);
+            if starting {
+                shared.starting.fetch_sub(1, Ordering::SeqCst);
+                starting = false;
+            }
+
+            // Idle workers queue up for the receiver, time spent waiting for it
+            // counts towards `keep_alive` too
+            let receiver = shared.receiver.lock().unwrap();
+            let wait = shared.keep_alive.saturating_sub(idle_since.elapsed());
+            let message = receiver.recv_timeout(wait);
+            drop(receiver);
+            shared.idle.fetch_sub(1, Ordering::SeqCst);
+
             match message {
                 Ok(job) => {
                     // println!("Worker {id} got a job; executing.");
 
-                    job();
+                    shared.queued.fetch_sub(1, Ordering::SeqCst);
+                    // e.g. a handler unwrapping a write to a client that went away
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        println!("Worker {id} job panicked.");
+                    }
+                    idle_since = Instant::now();
                 }
-                Err(_) => {
+                Err(RecvTimeoutError::Timeout) => {
+                    idle_since = Instant::now();
+                    let shrunk =
+                        shared
+                            .size
+                            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                                (size > shared.min).then(|| size - 1)
+                            });
+                    if shrunk.is_ok() {
+                        println!("Worker {id} idle; shutting down.");
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
                     println!("Worker {id} disconnected; shutting down.");
                     break;
                 }
@@ -79,4 +187,72 @@ impl Worker {
             thread: Some(thread),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Condvar;
+
+    #[test]
+    fn burst_grows_the_pool() {
+        const JOBS: usize = 8;
+        let pool = ThreadPool::new(1, 64, Duration::from_secs(60));
+        // Jobs running, and whether they may finish
+        let state = Arc::new((Mutex::new((0, false)), Condvar::new()));
+
+        for _ in 0..JOBS {
+            let state = Arc::clone(&state);
+            pool.excute(move || {
+                let (lock, cvar) = &*state;
+                let mut guard = lock.lock().unwrap();
+                guard.0 += 1;
+                cvar.notify_all();
+                let _guard = cvar.wait_while(guard, |(_, release)| !*release).unwrap();
+            });
+        }
+
+        let (lock, cvar) = &*state;
+        let guard = lock.lock().unwrap();
+        let (mut guard, _) = cvar
+            .wait_timeout_while(guard, Duration::from_secs(5), |(running, _)| {
+                *running < JOBS
+            })
+            .unwrap();
+        let running = guard.0;
+        guard.1 = true;
+        cvar.notify_all();
+        drop(guard);
+        assert_eq!(running, JOBS);
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::new(1, 1, Duration::from_secs(60));
+        pool.excute(|| panic!("job failed"));
+        let (sender, receiver) = mpsc::channel();
+        pool.excute(move || sender.send(()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn idle_workers_shut_down() {
+        let pool = ThreadPool::new(1, 4, Duration::from_millis(100));
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..4 {
+            let sender = sender.clone();
+            pool.excute(move || {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(()).unwrap();
+            });
+        }
+        for _ in 0..4 {
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.shared.size.load(Ordering::SeqCst) > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(pool.shared.size.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,654 @@
+use crate::context::{Context, ContextFn};
+use crate::cookie::http_date;
+use crate::header::HeaderMap;
+use crate::hex;
+use crate::response::status::Status;
+use crate::response::{NoneContent, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, prelude::*, Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/*
+Resumable uploads with the tus 1.0 protocol (https://tus.io/protocols/resumable-upload),
+core protocol plus the creation, termination and expiration extensions.
+
+    let tus = TusConfig::new("uploads")?
+        .max_size(4 << 30)
+        .on_complete(|upload| println!("{} done: {:?}", upload.id, upload.metadata));
+    web.tus("/files", tus);
+
+    POST   /files         Upload-Length: 1000         -> 201, Location: /files/<id>
+    HEAD   /files/<id>                                -> 200, Upload-Offset: 400
+    PATCH  /files/<id>    Upload-Offset: 400, bytes   -> 204, Upload-Offset: 1000
+    DELETE /files/<id>                                -> 204
+
+Every upload is a data file named by its id in the storage directory, plus
+`<id>.info` with its length and metadata. The offset is the size of the data file,
+so whatever arrived before a connection broke is kept and the client continues
+from there. Unfinished uploads expire after not being appended to for a while,
+finished ones stay until they're deleted.
+*/
+
+pub const TUS_VERSION: &str = "1.0.0";
+const EXTENSIONS: &str = "creation,expiration,termination";
+// Upload ids are this many random bytes as hex
+const ID_BYTES: usize = 16;
+
+// Expired uploads are looked for at most this often
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct TusConfig {
+    dir: PathBuf,
+    max_size: u64,
+    expiration: Duration,
+    on_complete: Option<fn(&TusUpload)>,
+    // Where the routes are, for Location
+    path: String,
+}
+
+impl TusConfig {
+    // Uploads are stored in `dir`, which is created if needed
+    pub fn new(dir: &str) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            max_size: 1 << 30,
+            expiration: Duration::from_secs(24 * 60 * 60),
+            on_complete: None,
+            path: String::new(),
+        })
+    }
+
+    // Largest upload, 1GB by default
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    // How long an unfinished upload is kept after it was last appended to, a day
+    // by default
+    pub fn expiration(mut self, expiration: Duration) -> Self {
+        self.expiration = expiration;
+        self
+    }
+
+    // Called once the last byte of an upload arrived
+    pub fn on_complete(mut self, on_complete: fn(&TusUpload)) -> Self {
+        self.on_complete = Some(on_complete);
+        self
+    }
+}
+
+static TUS: OnceLock<TusConfig> = OnceLock::new();
+static LAST_CLEANUP: Mutex<Option<Instant>> = Mutex::new(None);
+// Uploads with a PATCH going on, a second one is refused
+static BUSY: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+// The route path without a trailing '/' and the body limit for PATCH. There's only
+// one tus endpoint, a second call panics.
+pub(crate) fn set_config(path: &str, mut config: TusConfig) -> (String, usize) {
+    config.path = path.trim_end_matches('/').to_string();
+    let route = (
+        config.path.clone(),
+        config.max_size.min(usize::MAX as u64) as usize,
+    );
+    if TUS.set(config).is_err() {
+        panic!("tus is already set up");
+    }
+    route
+}
+
+#[derive(Debug, Clone)]
+pub struct TusUpload {
+    pub id: String,
+    // The data file
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+    // Decoded `Upload-Metadata`, keys without value map to ""
+    pub metadata: HashMap<String, String>,
+}
+
+impl TusUpload {
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length
+    }
+
+    // Delete the data and info files, e.g. after the data was moved elsewhere
+    pub fn remove(&self) -> Result<()> {
+        remove_files(&config_or_err()?.dir, &self.id)
+    }
+}
+
+// An upload that's still stored
+pub fn find(id: &str) -> Option<TusUpload> {
+    let config = TUS.get()?;
+    let (info, offset, _) = load(config, id).ok()??;
+    Some(upload(config, id, &info, offset))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Info {
+    length: u64,
+    // `Upload-Metadata` as sent
+    metadata: String,
+}
+
+// The handler of the tus routes, for any method
+pub(crate) fn handler(mut c: Context) {
+    let config = match TUS.get() {
+        Some(config) => config,
+        None => return c.error_with_status(Status::NotFound),
+    };
+
+    // For clients that can't send PATCH or DELETE
+    let mut method = c.request.header_first("X-HTTP-Method-Override");
+    if method.is_empty() {
+        method = c.request.method.clone();
+    }
+
+    let result = if method == "OPTIONS" {
+        options(config)
+    } else if c.request.header_first("Tus-Resumable") != TUS_VERSION {
+        let mut header = HeaderMap::new();
+        header.append("Tus-Version", TUS_VERSION).unwrap();
+        Ok((Status::PreconditionFailed, header))
+    } else {
+        let id = c.request.param("id");
+        match (method.as_str(), id.is_empty()) {
+            ("POST", true) => create(&mut c, config),
+            ("HEAD", false) => head(config, &id),
+            ("PATCH", false) => patch(&mut c, config, &id),
+            ("DELETE", false) => terminate(config, &id),
+            _ => Ok((Status::MethodNotAllowed, HeaderMap::new())),
+        }
+    };
+
+    match result {
+        Ok((status, mut header)) => {
+            header.append("Tus-Resumable", TUS_VERSION).unwrap();
+            c.respond(Response::new(status, header, Box::new(NoneContent)));
+        }
+        Err(err) => {
+            println!("tus error: {}", err);
+            c.respond(err);
+        }
+    }
+}
+
+type Reply = Result<(Status, HeaderMap)>;
+
+fn options(config: &TusConfig) -> Reply {
+    let mut header = HeaderMap::new();
+    header.append("Tus-Version", TUS_VERSION)?;
+    header.append("Tus-Extension", EXTENSIONS)?;
+    header.append("Tus-Max-Size", &config.max_size.to_string())?;
+    Ok((Status::NoContent, header))
+}
+
+fn create(c: &mut Context, config: &TusConfig) -> Reply {
+    let length: u64 = match c.request.header_first("Upload-Length").parse() {
+        Ok(length) => length,
+        // Upload-Defer-Length isn't supported
+        Err(_) => return Ok((Status::BadRequest, HeaderMap::new())),
+    };
+    if length > config.max_size {
+        return Ok((Status::ContentTooLarge, HeaderMap::new()));
+    }
+    let metadata = c.request.header_first("Upload-Metadata");
+    if parse_metadata(&metadata).is_err() {
+        return Ok((Status::BadRequest, HeaderMap::new()));
+    }
+
+    remove_expired(config);
+
+    let id = hex::random(ID_BYTES, "upload ids");
+    File::options()
+        .write(true)
+        .create_new(true)
+        .open(config.dir.join(&id))?;
+    let info = Info { length, metadata };
+    fs::write(info_path(config, &id), serde_json::to_vec(&info)?)?;
+
+    let mut header = HeaderMap::new();
+    header.append("Location", &format!("{}/{}", config.path, id))?;
+    if length == 0 {
+        complete(config, &id, &info);
+    } else {
+        header.append("Upload-Expires", &expires(config))?;
+    }
+    Ok((Status::Created, header))
+}
+
+fn head(config: &TusConfig, id: &str) -> Reply {
+    let (info, offset, modified) = match load(config, id)? {
+        Some(upload) => upload,
+        None => return Ok((Status::NotFound, HeaderMap::new())),
+    };
+    if is_expired(config, &info, offset, modified) {
+        remove_files(&config.dir, id)?;
+        return Ok((Status::Gone, HeaderMap::new()));
+    }
+
+    let mut header = HeaderMap::new();
+    header.append("Upload-Offset", &offset.to_string())?;
+    header.append("Upload-Length", &info.length.to_string())?;
+    if !info.metadata.is_empty() {
+        header.append("Upload-Metadata", &info.metadata)?;
+    }
+    if offset < info.length {
+        header.append("Upload-Expires", &http_date(modified + config.expiration))?;
+    }
+    header.append("Cache-Control", "no-store")?;
+    Ok((Status::OK, header))
+}
+
+fn patch(c: &mut Context, config: &TusConfig, id: &str) -> Reply {
+    if c.request.header_first("Content-Type") != "application/offset+octet-stream" {
+        return Ok((Status::UnsupportedMediaType, HeaderMap::new()));
+    }
+    let offset: u64 = match c.request.header_first("Upload-Offset").parse() {
+        Ok(offset) => offset,
+        Err(_) => return Ok((Status::BadRequest, HeaderMap::new())),
+    };
+
+    let _lock = match Lock::take(id) {
+        Some(lock) => lock,
+        None => return Ok((Status::Locked, HeaderMap::new())),
+    };
+    let (info, current, modified) = match load(config, id)? {
+        Some(upload) => upload,
+        None => return Ok((Status::NotFound, HeaderMap::new())),
+    };
+    if is_expired(config, &info, current, modified) {
+        remove_files(&config.dir, id)?;
+        return Ok((Status::Gone, HeaderMap::new()));
+    }
+    if offset != current {
+        return Ok((Status::Conflict, HeaderMap::new()));
+    }
+    let remaining = info.length - offset;
+    if c.request.content_length as u64 > remaining {
+        return Ok((Status::BadRequest, HeaderMap::new()));
+    }
+
+    // What arrives is kept even if the connection breaks, the client resumes there.
+    // Not so for a body that's over a limit or comes with a digest, it's only kept
+    // once it's complete and matches.
+    let verified = !c.request.expects_body_digest();
+    let mut file = File::options().append(true).open(config.dir.join(id))?;
+    let mut body = c.request.body_reader();
+    let copied = io::copy(&mut (&mut body).take(remaining), &mut file);
+    let written = match copied {
+        Ok(written) => written,
+        Err(err) => {
+            if !verified || err.kind() == ErrorKind::InvalidData {
+                file.set_len(offset)?;
+            }
+            file.sync_all()?;
+            return Err(err);
+        }
+    };
+
+    // Reaching the end checks the digest. A chunked body may also go on past the
+    // length.
+    let end = body.read(&mut [0]);
+    if !matches!(end, Ok(0)) {
+        file.set_len(offset)?;
+        file.sync_all()?;
+        end?;
+        return Ok((Status::BadRequest, HeaderMap::new()));
+    }
+    file.sync_all()?;
+
+    let offset = offset + written;
+    let mut header = HeaderMap::new();
+    header.append("Upload-Offset", &offset.to_string())?;
+    if offset == info.length {
+        complete(config, id, &info);
+    } else {
+        header.append("Upload-Expires", &expires(config))?;
+    }
+    Ok((Status::NoContent, header))
+}
+
+fn terminate(config: &TusConfig, id: &str) -> Reply {
+    let _lock = match Lock::take(id) {
+        Some(lock) => lock,
+        None => return Ok((Status::Locked, HeaderMap::new())),
+    };
+    if load(config, id)?.is_none() {
+        return Ok((Status::NotFound, HeaderMap::new()));
+    }
+    remove_files(&config.dir, id)?;
+    Ok((Status::NoContent, HeaderMap::new()))
+}
+
+fn complete(config: &TusConfig, id: &str, info: &Info) {
+    if let Some(on_complete) = config.on_complete {
+        on_complete(&upload(config, id, info, info.length));
+    }
+}
+
+fn upload(config: &TusConfig, id: &str, info: &Info, offset: u64) -> TusUpload {
+    TusUpload {
+        id: id.to_string(),
+        path: config.dir.join(id),
+        offset,
+        length: info.length,
+        metadata: parse_metadata(&info.metadata).unwrap_or_default(),
+    }
+}
+
+// Info, offset and last modification of an upload, None if there's no such upload
+fn load(config: &TusConfig, id: &str) -> Result<Option<(Info, u64, SystemTime)>> {
+    if !hex::is_random(id, ID_BYTES) {
+        return Ok(None);
+    }
+    let info = match fs::read(info_path(config, id)) {
+        Ok(info) => info,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let info: Info = serde_json::from_slice(&info)?;
+    let metadata = match fs::metadata(config.dir.join(id)) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    Ok(Some((info, metadata.len(), metadata.modified()?)))
+}
+
+// Only unfinished uploads expire
+fn is_expired(config: &TusConfig, info: &Info, offset: u64, modified: SystemTime) -> bool {
+    offset < info.length && modified + config.expiration < SystemTime::now()
+}
+
+fn expires(config: &TusConfig) -> String {
+    http_date(SystemTime::now() + config.expiration)
+}
+
+fn remove_expired(config: &TusConfig) {
+    {
+        let mut last_cleanup = LAST_CLEANUP.lock().unwrap();
+        if last_cleanup.is_some_and(|last| last.elapsed() < CLEANUP_INTERVAL) {
+            return;
+        }
+        *last_cleanup = Some(Instant::now());
+    }
+
+    let entries = match fs::read_dir(&config.dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let id = match name.strip_suffix(".info") {
+            Some(id) => id,
+            None => continue,
+        };
+        if let Ok(Some((info, offset, modified))) = load(config, id) {
+            if is_expired(config, &info, offset, modified) {
+                let _ = remove_files(&config.dir, id);
+            }
+        }
+    }
+}
+
+fn remove_files(dir: &Path, id: &str) -> Result<()> {
+    for path in [dir.join(id), dir.join(format!("{}.info", id))] {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn info_path(config: &TusConfig, id: &str) -> PathBuf {
+    config.dir.join(format!("{}.info", id))
+}
+
+fn config_or_err() -> Result<&'static TusConfig> {
+    TUS.get()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "tus is not set up"))
+}
+
+// "filename d29ybGQucGRm,is_confidential": comma separated keys, each followed by
+// its base64 encoded value if it has one
+fn parse_metadata(value: &str) -> Result<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, encoded) = match pair.split_once(' ') {
+            Some((key, encoded)) => (key, encoded.trim()),
+            None => (pair, ""),
+        };
+        let decoded = STANDARD
+            .decode(encoded)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid Upload-Metadata"))?;
+        let decoded = String::from_utf8_lossy(&decoded).to_string();
+        if metadata.insert(key.to_string(), decoded).is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "duplicate Upload-Metadata key",
+            ));
+        }
+    }
+    Ok(metadata)
+}
+
+// Marks an upload busy until dropped
+struct Lock(String);
+
+impl Lock {
+    fn take(id: &str) -> Option<Lock> {
+        let mut busy = BUSY.get_or_init(Default::default).lock().unwrap();
+        busy.insert(id.to_string()).then(|| Lock(id.to_string()))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Some(busy) = BUSY.get() {
+            busy.lock().unwrap().remove(&self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::from_raw;
+
+    // A config with an empty directory of its own
+    fn config(name: &str) -> TusConfig {
+        let dir = std::env::temp_dir().join(format!("rustweb-tus-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = TusConfig::new(dir.to_str().unwrap()).unwrap();
+        config.path = "/files".to_string();
+        config
+    }
+
+    fn create_upload(config: &TusConfig, length: u64) -> String {
+        let mut c = from_raw(
+            format!("POST /files HTTP/1.1\r\nUpload-Length: {}\r\n\r\n", length).as_bytes(),
+        );
+        let (status, header) = create(&mut c, config).unwrap();
+        assert_eq!(status, Status::Created);
+        let location = header.get("Location").unwrap();
+        location.strip_prefix("/files/").unwrap().to_string()
+    }
+
+    // PATCH `body` at `offset`, `fields` are extra header lines
+    fn patch_upload(config: &TusConfig, id: &str, offset: u64, fields: &str, body: &str) -> Reply {
+        let fields = format!("Content-Length: {}\r\n{}", body.len(), fields);
+        patch_raw(config, id, offset, &fields, body, None)
+    }
+
+    fn patch_raw(
+        config: &TusConfig,
+        id: &str,
+        offset: u64,
+        fields: &str,
+        body: &str,
+        body_limit: Option<usize>,
+    ) -> Reply {
+        let mut c = from_raw(
+            format!(
+                "PATCH /files/{} HTTP/1.1\r\nContent-Type: application/offset+octet-stream\r\n\
+                 Upload-Offset: {}\r\n{}\r\n{}",
+                id, offset, fields, body
+            )
+            .as_bytes(),
+        );
+        if let Some(limit) = body_limit {
+            c.request.body_limit = limit;
+        }
+        patch(&mut c, config, id)
+    }
+
+    fn offset(config: &TusConfig, id: &str) -> u64 {
+        let (status, header) = head(config, id).unwrap();
+        assert_eq!(status, Status::OK);
+        header.get("Upload-Offset").unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn digest_mismatch_rolls_back() {
+        let config = config("digest");
+        let id = create_upload(&config, 10);
+        let (status, _) = patch_upload(&config, &id, 0, "", "abc").unwrap();
+        assert_eq!(status, Status::NoContent);
+
+        // md5 of "hello", not of what's sent
+        let md5 = "Content-MD5: XUFAKrxLKna5cZ2REBfFkg==\r\n";
+        let err = patch_upload(&config, &id, 3, md5, "world").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(offset(&config, &id), 3);
+
+        let (status, header) = patch_upload(&config, &id, 3, md5, "hello").unwrap();
+        assert_eq!(status, Status::NoContent);
+        assert_eq!(header.get("Upload-Offset"), Some("8"));
+        assert_eq!(fs::read(config.dir.join(&id)).unwrap(), b"abchello");
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn upload_length_limit() {
+        let config = config("length").max_size(100);
+        let mut c = from_raw(b"POST /files HTTP/1.1\r\nUpload-Length: 101\r\n\r\n");
+        let (status, _) = create(&mut c, &config).unwrap();
+        assert_eq!(status, Status::ContentTooLarge);
+        let mut c = from_raw(b"POST /files HTTP/1.1\r\nUpload-Length: -1\r\n\r\n");
+        assert_eq!(create(&mut c, &config).unwrap().0, Status::BadRequest);
+
+        let id = create_upload(&config, 100);
+        assert_eq!(offset(&config, &id), 0);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn offset_mismatch_conflicts() {
+        let config = config("conflict");
+        let id = create_upload(&config, 10);
+        let (status, _) = patch_upload(&config, &id, 2, "", "abc").unwrap();
+        assert_eq!(status, Status::Conflict);
+        assert_eq!(offset(&config, &id), 0);
+
+        patch_upload(&config, &id, 0, "", "abc").unwrap();
+        let (status, _) = patch_upload(&config, &id, 0, "", "abc").unwrap();
+        assert_eq!(status, Status::Conflict);
+        // More than what's left of the upload
+        let (status, _) = patch_upload(&config, &id, 3, "", "12345678").unwrap();
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(offset(&config, &id), 3);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        let config = config("ids");
+        fs::write(config.dir.join("secret"), "x").unwrap();
+        fs::write(
+            config.dir.join("secret.info"),
+            r#"{"length":1,"metadata":""}"#,
+        )
+        .unwrap();
+        let id = create_upload(&config, 10);
+
+        for bad in [
+            "secret",
+            "../secret",
+            &id[1..],
+            &id.to_uppercase(),
+            &format!("{}0", id),
+        ] {
+            assert_eq!(head(&config, bad).unwrap().0, Status::NotFound, "{}", bad);
+            assert_eq!(
+                terminate(&config, bad).unwrap().0,
+                Status::NotFound,
+                "{}",
+                bad
+            );
+            let (status, _) = patch_upload(&config, bad, 0, "", "x").unwrap();
+            assert_eq!(status, Status::NotFound, "{}", bad);
+        }
+        assert!(config.dir.join("secret").exists());
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn head_after_partial_patch() {
+        let config = config("partial");
+        let id = create_upload(&config, 10);
+        // The connection breaks after 3 of 6 bytes, those are kept
+        let err = patch_raw(&config, &id, 0, "Content-Length: 6\r\n", "abc", None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let (status, header) = head(&config, &id).unwrap();
+        assert_eq!(status, Status::OK);
+        assert_eq!(header.get("Upload-Offset"), Some("3"));
+        assert_eq!(header.get("Upload-Length"), Some("10"));
+        assert!(header.contains_key("Upload-Expires"));
+        assert_eq!(header.get("Cache-Control"), Some("no-store"));
+
+        let (status, header) = patch_upload(&config, &id, 3, "", "defghij").unwrap();
+        assert_eq!(status, Status::NoContent);
+        assert_eq!(header.get("Upload-Offset"), Some("10"));
+        assert!(!header.contains_key("Upload-Expires"));
+        assert_eq!(fs::read(config.dir.join(&id)).unwrap(), b"abcdefghij");
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn body_limit_rolls_back() {
+        let config = config("limit");
+        let id = create_upload(&config, 10);
+        patch_upload(&config, &id, 0, "", "ab").unwrap();
+
+        let chunked = "Transfer-Encoding: chunked\r\n";
+        let err = patch_raw(
+            &config,
+            &id,
+            2,
+            chunked,
+            "3\r\ncde\r\n3\r\nfgh\r\n0\r\n\r\n",
+            Some(4),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(offset(&config, &id), 2);
+        assert_eq!(fs::read(config.dir.join(&id)).unwrap(), b"ab");
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}